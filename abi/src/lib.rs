@@ -0,0 +1,226 @@
+//! The interface between the kernel and userspace: syscall numbers, how arguments and results
+//! are passed, and what can go wrong.
+//!
+//! To make a syscall, put its number in x8 and its arguments in x0 upwards, then `svc #0`. The
+//! result comes back in x0, encoded as described by [`encode_result`], and every other register
+//! is preserved.
+
+#![no_std]
+
+/// Every syscall, numbered as they're passed in x8.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(usize)]
+pub enum Syscall {
+    /// `exit(code: i32) -> !`
+    Exit = 0,
+    /// `print(base: *const u8, len: usize)`: write some bytes to the console
+    Print = 1,
+    /// `yield()`: let something else run for a while
+    Yield = 2,
+    /// `spawn(path: *const u8) -> pid`: start running the program at the nul-terminated `path`
+    /// in the initrd
+    Spawn = 3,
+    /// `wait(pid: usize) -> code`: wait for a child process to exit, and return its exit code,
+    /// zero extended from 32 bits
+    Wait = 4,
+    /// `fork() -> pid`: make a copy of the calling process. Returns 0 in the child.
+    Fork = 5,
+    /// `map(addr: usize, len: usize, prot: usize, flags: usize) -> addr`: map some zeroed
+    /// memory, with the permissions in [`prot`]. `addr` is only a hint unless [`map::FIXED`] is
+    /// given, and 0 lets the kernel choose. `len` is rounded up to a whole number of pages.
+    Map = 6,
+    /// `unmap(addr: usize, len: usize)`: unmap every page in `addr..addr + len`, some of which
+    /// might not be mapped anyway. `addr` must be page aligned and `len` is rounded up.
+    Unmap = 7,
+    /// `protect(addr: usize, len: usize, prot: usize)`: change the permissions of every page in
+    /// `addr..addr + len`, all of which must be mapped
+    Protect = 8,
+    /// `reboot(how: usize) -> !`: power the machine off or restart it, as given by one of the
+    /// constants in [`reboot`]. Only init is allowed to.
+    Reboot = 9,
+    /// `read(base: *mut u8, len: usize) -> len`: read some input from the console, waiting for
+    /// some to arrive if there isn't any. Returns how many bytes were read, which is only 0 if
+    /// `len` is.
+    Read = 10,
+}
+
+impl Syscall {
+    pub fn from_number(num: usize) -> Option<Self> {
+        Some(match num {
+            0 => Syscall::Exit,
+            1 => Syscall::Print,
+            2 => Syscall::Yield,
+            3 => Syscall::Spawn,
+            4 => Syscall::Wait,
+            5 => Syscall::Fork,
+            6 => Syscall::Map,
+            7 => Syscall::Unmap,
+            8 => Syscall::Protect,
+            9 => Syscall::Reboot,
+            10 => Syscall::Read,
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(usize)]
+pub enum Error {
+    /// There's no syscall with that number
+    InvalidSyscall = 1,
+    /// Some of the memory passed to the kernel isn't mapped, or doesn't allow the access
+    InvalidPointer = 2,
+    /// There's no file at the given path
+    NotFound = 3,
+    /// The file isn't something that can be run
+    InvalidExecutable = 4,
+    /// There's no such process, or it isn't a child of the caller
+    NoSuchProcess = 5,
+    OutOfMemory = 6,
+    /// An argument doesn't make sense, e.g. an unknown flag or an unaligned address
+    InvalidArgument = 7,
+    /// The caller isn't allowed to do that
+    NotPermitted = 8,
+    /// An error this version of the ABI doesn't know about
+    Unknown = MAX_ERROR,
+}
+
+/// Memory permissions, as passed to [`Syscall::Map`] and [`Syscall::Protect`]
+pub mod prot {
+    pub const READ: usize = 1 << 0;
+    pub const WRITE: usize = 1 << 1;
+    /// Can't be combined with `WRITE`
+    pub const EXEC: usize = 1 << 2;
+}
+
+/// Flags for [`Syscall::Map`]
+pub mod map {
+    /// Map at exactly the address given, failing if anything is already there
+    pub const FIXED: usize = 1 << 0;
+}
+
+/// What [`Syscall::Reboot`] should do
+pub mod reboot {
+    pub const POWER_OFF: usize = 0;
+    pub const RESTART: usize = 1;
+}
+
+/// Error codes go up to this, so results from `usize::MAX - MAX_ERROR + 1` up are errors
+const MAX_ERROR: usize = 4095;
+
+impl Error {
+    pub fn from_code(code: usize) -> Self {
+        match code {
+            1 => Error::InvalidSyscall,
+            2 => Error::InvalidPointer,
+            3 => Error::NotFound,
+            4 => Error::InvalidExecutable,
+            5 => Error::NoSuchProcess,
+            6 => Error::OutOfMemory,
+            7 => Error::InvalidArgument,
+            8 => Error::NotPermitted,
+            _ => Error::Unknown,
+        }
+    }
+}
+
+/// Turn the result of a syscall into what's returned in x0: the value itself on success, or the
+/// negated error code on failure. Successful results can't be in the top 4095 values, which
+/// nothing the kernel returns ever is.
+pub fn encode_result(res: Result<usize, Error>) -> usize {
+    match res {
+        Ok(value) => value,
+        Err(e) => (e as usize).wrapping_neg(),
+    }
+}
+
+/// The inverse of [`encode_result`].
+pub fn decode_result(raw: usize) -> Result<usize, Error> {
+    match raw.wrapping_neg() {
+        code @ 1..=MAX_ERROR => Err(Error::from_code(code)),
+        _ => Ok(raw),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SYSCALLS: &[Syscall] = &[
+        Syscall::Exit,
+        Syscall::Print,
+        Syscall::Yield,
+        Syscall::Spawn,
+        Syscall::Wait,
+        Syscall::Fork,
+        Syscall::Map,
+        Syscall::Unmap,
+        Syscall::Protect,
+        Syscall::Reboot,
+        Syscall::Read,
+    ];
+
+    const ERRORS: &[Error] = &[
+        Error::InvalidSyscall,
+        Error::InvalidPointer,
+        Error::NotFound,
+        Error::InvalidExecutable,
+        Error::NoSuchProcess,
+        Error::OutOfMemory,
+        Error::InvalidArgument,
+        Error::NotPermitted,
+        Error::Unknown,
+    ];
+
+    #[test]
+    fn syscall_numbers_round_trip() {
+        for &syscall in SYSCALLS {
+            assert_eq!(Syscall::from_number(syscall as usize), Some(syscall));
+        }
+        assert_eq!(Syscall::from_number(SYSCALLS.len()), None);
+        assert_eq!(Syscall::from_number(usize::MAX), None);
+    }
+
+    #[test]
+    fn error_codes_round_trip() {
+        for &error in ERRORS {
+            assert_eq!(Error::from_code(error as usize), error);
+        }
+    }
+
+    #[test]
+    fn unknown_error_codes() {
+        assert_eq!(Error::from_code(0), Error::Unknown);
+        assert_eq!(Error::from_code(ERRORS.len()), Error::Unknown);
+        assert_eq!(Error::from_code(MAX_ERROR - 1), Error::Unknown);
+        // an error from a newer kernel is still an error
+        assert_eq!(decode_result(9usize.wrapping_neg()), Err(Error::Unknown));
+    }
+
+    #[test]
+    fn results_round_trip() {
+        for &error in ERRORS {
+            assert_eq!(decode_result(encode_result(Err(error))), Err(error));
+        }
+        for value in [0, 1, 4096, usize::MAX / 2, usize::MAX - MAX_ERROR] {
+            assert_eq!(decode_result(encode_result(Ok(value))), Ok(value));
+        }
+    }
+
+    #[test]
+    fn max_error_boundary() {
+        // the lowest value that's an error, and the highest that isn't
+        assert_eq!(
+            decode_result(usize::MAX - MAX_ERROR + 1),
+            Err(Error::Unknown)
+        );
+        assert_eq!(
+            decode_result(usize::MAX - MAX_ERROR),
+            Ok(usize::MAX - MAX_ERROR)
+        );
+        assert_eq!(
+            encode_result(Err(Error::Unknown)),
+            usize::MAX - MAX_ERROR + 1
+        );
+    }
+}
@@ -1,41 +1,19 @@
-#![feature(naked_functions)]
 #![no_main]
 #![no_std]
 
-use core::{arch::asm, fmt::Write};
+extern crate alloc;
 
-#[naked]
-extern "C" fn sys_print(s: *const u8, len: usize) {
-    unsafe {
-        asm!("svc #1; ret", options(noreturn));
-    }
-}
+use alloc::vec::Vec;
 
-#[naked]
-extern "C" fn sys_yield() {
-    unsafe {
-        asm!("svc #2; ret", options(noreturn));
-    }
-}
+use libkernel::{println, syscall};
 
-struct Stdout;
-impl Write for Stdout {
-    fn write_str(&mut self, s: &str) -> core::fmt::Result {
-        sys_print(s.as_ptr(), s.len());
-        Ok(())
-    }
-}
-
-#[no_mangle]
-fn _start() {
-    let _ = writeln!(Stdout, "Hello, world!");
-    sys_yield();
-    let _ = writeln!(Stdout, "I'm having a great time in userspace");
-    sys_yield();
-    unsafe { asm!("svc #0", options(noreturn)) }
-}
+libkernel::entry!(main);
 
-#[panic_handler]
-fn panic_handler(_: &core::panic::PanicInfo) -> ! {
-    unsafe { asm!("svc #0", options(noreturn)) }
+fn main() -> i32 {
+    let args: Vec<&str> = libkernel::env::args().collect();
+    println!("Hello, world! I was started as {args:?}");
+    syscall::yield_now();
+    println!("I'm having a great time in userspace");
+    syscall::yield_now();
+    0
 }
@@ -1,24 +1,41 @@
-use core::{arch::asm, fmt::Display};
+use core::{arch::asm, fmt::Display, sync::atomic::Ordering};
 
 use crate::{
     context::Context,
-    vm::{PhysicalAddress, Table, VirtualAddress},
+    memory::frame::FrameOwner,
+    vm::{PhysicalAddress, VirtualAddress},
 };
 
-use super::vm::TopLevelTable;
+use super::{
+    percpu,
+    vm::{
+        asid::{self, Asid},
+        phys_to_virt, TopLevelTable,
+    },
+    FRAME_SIZE,
+};
 
 pub struct SuspendedContext {
     table: PhysicalAddress,
+    asid: Asid,
 
     registers: Registers,
     sp: VirtualAddress,
+    /// The thread pointer, which userspace can use however it likes
+    tpidr: usize,
     elr: VirtualAddress,
     spsr: u64,
 }
 
+#[derive(Clone)]
 pub struct ActiveContext {
     pub(super) registers: Registers,
-    // sp, elr, spsr are stored in their registers upon context entry
+    // elr and spsr are clobbered by any exception taken in the kernel, e.g. a page fault while
+    // copying something into user memory, so they're kept here and only put back in their
+    // registers right before returning to userspace
+    pub(super) elr: VirtualAddress,
+    pub(super) spsr: u64,
+    // sp and tpidr are stored in their registers upon context entry
 }
 
 #[derive(Clone)]
@@ -27,85 +44,209 @@ pub(super) struct Registers {
     pub x: [usize; 31],
 }
 
+/// Where kernel code left off when it switched away from a context: the registers that a function
+/// call preserves, which are all that's needed to carry on as if the switch was just a call.
+/// switch.S depends on the layout.
+#[derive(Default)]
+#[repr(C)]
+pub struct KernelState {
+    x19_x28: [usize; 10],
+    fp: usize,
+    /// Where the switch returns to
+    lr: usize,
+    sp: usize,
+    d8_d15: [u64; 8],
+}
+
+extern "C" {
+    fn switch_kernel_state(from: *mut KernelState, to: *const KernelState);
+    fn resume_kernel_state(to: *const KernelState) -> !;
+    fn kernel_state_start();
+}
+
+impl KernelState {
+    /// A state that calls `entry(arg)` on the stack that ends at `stack_top`.
+    pub fn new(stack_top: VirtualAddress, entry: extern "C" fn(usize) -> !, arg: usize) -> Self {
+        let mut state = KernelState::default();
+        state.x19_x28[0] = entry as usize;
+        state.x19_x28[1] = arg;
+        state.lr = kernel_state_start as usize;
+        state.sp = stack_top.0;
+        state
+    }
+
+    /// Save where the caller is in `self` and carry on from `to` instead. Returns when something
+    /// [`resume`](Self::resume)s or switches to `self`.
+    ///
+    /// # Safety
+    /// `to` must be a state that was saved on a stack that's still there, or a new one, and
+    /// nothing else can be using its stack.
+    pub unsafe fn switch(&mut self, to: &KernelState) {
+        switch_kernel_state(self, to)
+    }
+
+    /// Carry on from `self`, abandoning whatever the caller was doing.
+    ///
+    /// # Safety
+    /// As for [`switch`](Self::switch).
+    pub unsafe fn resume(&self) -> ! {
+        resume_kernel_state(self)
+    }
+}
+
+/// The context that's active on this CPU.
+pub fn current() -> *const Context {
+    percpu::this().current.load(Ordering::Relaxed)
+}
+
 impl SuspendedContext {
     pub fn new() -> Self {
         let table = crate::memory::FRAME_ALLOCATOR.lock().alloc();
+        crate::memory::frame::set_owner(table, FrameOwner::PageTable);
+        // nothing is mapped until the table is initialised, which kernel threads never bother to
+        // do, so it mustn't have junk in it
+        unsafe { core::ptr::write_bytes(phys_to_virt(table).0 as *mut u8, 0, FRAME_SIZE) };
         SuspendedContext {
             table,
+            asid: Asid::NONE,
             registers: Registers { x: [0; 31] },
             sp: VirtualAddress(0),
+            tpidr: 0,
             elr: VirtualAddress(0),
             // TODO: Very dangerous and bad please review
             spsr: 0,
         }
     }
 
+    /// Free the top level page table. Everything below it must have been freed already, by
+    /// clearing the context's address space.
+    pub fn free(self) {
+        asid::release(self.asid);
+        crate::memory::frame::put(self.table);
+    }
+
     pub fn enter(self, context: *const Context) -> ActiveContext {
         let SuspendedContext {
             table,
+            mut asid,
             registers,
             sp,
+            tpidr,
             elr,
             spsr,
         } = self;
         unsafe {
-            super::vm::switch_table(table);
+            super::vm::switch_table(table, &mut asid);
             asm!("msr SP_EL0, {0}", in(reg) sp.0, options(nomem, nostack, preserves_flags));
-            asm!("msr ELR_EL1, {0}", in(reg) elr.0, options(nomem, nostack, preserves_flags));
-            asm!("msr SPSR_EL1, {0}", in(reg) spsr, options(nomem, nostack, preserves_flags));
-            asm!("msr TPIDR_EL0, {0}", in(reg) context, options(nomem, nostack, preserves_flags));
+            asm!("msr TPIDR_EL0, {0}", in(reg) tpidr, options(nomem, nostack, preserves_flags));
+        }
+        percpu::this()
+            .current
+            .store(context as *mut _, Ordering::Relaxed);
+        ActiveContext {
+            registers,
+            elr,
+            spsr,
         }
-        ActiveContext { registers }
     }
 }
 
 impl ActiveContext {
-    pub fn suspend(self) -> (SuspendedContext, VirtualAddress) {
-        let ActiveContext { registers } = self;
+    pub fn suspend(self) -> SuspendedContext {
+        let ActiveContext {
+            registers,
+            elr,
+            spsr,
+        } = self;
         let sp: usize;
-        let elr: usize;
-        let spsr: u64;
-        let thread: usize;
+        let tpidr: usize;
         let table;
         unsafe {
             asm!("mrs {0}, SP_EL0", out(reg) sp, options(nomem, nostack, preserves_flags));
-            asm!("mrs {0}, ELR_EL1", out(reg) elr, options(nomem, nostack, preserves_flags));
-            asm!("mrs {0}, SPSR_EL1", out(reg) spsr, options(nomem, nostack, preserves_flags));
-            asm!("mrs {0}, TPIDR_EL1", out(reg) thread, options(nomem, nostack, preserves_flags));
+            asm!("mrs {0}, TPIDR_EL0", out(reg) tpidr, options(nomem, nostack, preserves_flags));
             table = super::vm::get_current_user_table();
         }
-        let suspended = SuspendedContext {
+        SuspendedContext {
             table,
+            asid: asid::active(),
             registers,
             sp: VirtualAddress(sp),
+            tpidr,
+            elr,
+            spsr,
+        }
+    }
+
+    /// The state of a context that was interrupted in userspace, as saved by the exception
+    /// vector.
+    pub(super) fn interrupted(registers: &Registers) -> Self {
+        let elr: usize;
+        let spsr: u64;
+        unsafe {
+            asm!("mrs {0}, ELR_EL1", out(reg) elr, options(nomem, nostack, preserves_flags));
+            asm!("mrs {0}, SPSR_EL1", out(reg) spsr, options(nomem, nostack, preserves_flags));
+        }
+        ActiveContext {
+            registers: registers.clone(),
             elr: VirtualAddress(elr),
             spsr,
-        };
-        (suspended, VirtualAddress(thread))
+        }
     }
 
-    pub fn set_entry_point(&mut self, virt: VirtualAddress) {
+    /// Put the user state back where the exception return path expects it: the general purpose
+    /// registers in the frame saved by the exception vector, and the rest in their registers.
+    pub(super) fn restore(&self, registers: &mut Registers) {
+        registers.clone_from(&self.registers);
+        self.restore_exception_registers();
+    }
+
+    fn restore_exception_registers(&self) {
         unsafe {
-            asm!("msr ELR_EL1, {0}", in(reg) virt.0, options(nomem, nostack, preserves_flags))
+            asm!("msr ELR_EL1, {0}", in(reg) self.elr.0, options(nomem, nostack, preserves_flags));
+            asm!("msr SPSR_EL1, {0}", in(reg) self.spsr, options(nomem, nostack, preserves_flags));
         }
     }
 
+    pub fn set_entry_point(&mut self, virt: VirtualAddress) {
+        self.elr = virt;
+    }
+
+    pub fn stack_pointer(&self) -> VirtualAddress {
+        let sp: usize;
+        unsafe { asm!("mrs {0}, SP_EL0", out(reg) sp, options(nomem, nostack, preserves_flags)) };
+        VirtualAddress(sp)
+    }
+
     pub fn set_stack_pointer(&mut self, virt: VirtualAddress) {
         unsafe { asm!("msr SP_EL0, {0}", in(reg) virt.0, options(nomem, nostack, preserves_flags)) }
     }
 
-    pub fn syscall_params(&mut self) -> &mut [usize; 8] {
-        (&mut self.registers.x[0..8]).try_into().unwrap()
+    pub fn thread_pointer(&self) -> usize {
+        let tpidr: usize;
+        unsafe {
+            asm!("mrs {0}, TPIDR_EL0", out(reg) tpidr, options(nomem, nostack, preserves_flags))
+        };
+        tpidr
+    }
+
+    pub fn set_thread_pointer(&mut self, tpidr: usize) {
+        unsafe {
+            asm!("msr TPIDR_EL0, {0}", in(reg) tpidr, options(nomem, nostack, preserves_flags))
+        }
+    }
+
+    /// The number of the syscall being made, which is passed in x8.
+    pub fn syscall_number(&self) -> usize {
+        self.registers.x[8]
+    }
+
+    /// The arguments to the syscall being made, in x0 to x5. The result goes back in x0.
+    pub fn syscall_params(&mut self) -> &mut [usize; 6] {
+        (&mut self.registers.x[0..6]).try_into().unwrap()
     }
 
     pub fn init(&mut self) {
         super::vm::init_user_table(super::vm::get_current_user_table());
-        // safety: table has been initialised and recursive mapping has been set up by the previous
-        // line
-        let table = unsafe { self.table() };
-        let sp = VirtualAddress(0x0000_0000_7FFF_F000);
-        table.alloc(sp, 4096).unwrap();
-        self.set_stack_pointer(sp + 4096);
     }
 
     // lifetime of the table is tied to the lifetime of self because the pointer is invalidated in
@@ -116,15 +257,17 @@ impl ActiveContext {
         unsafe { &mut *(super::vm::USER_TABLE.0 as *mut _) }
     }
 
+    /// Return to userspace, throwing away everything on the kernel stack. The next exception
+    /// from userspace starts again at `stack_top`.
+    ///
     /// # Safety
     /// [`init`] must have been called before this function.
-    pub unsafe fn jump_to_userspace(&mut self) -> ! {
-        crate::tracing::RESET.store(true, core::sync::atomic::Ordering::Relaxed);
+    pub unsafe fn jump_to_userspace(&mut self, stack_top: VirtualAddress) -> ! {
+        crate::tracing::reset_current_span();
+        self.restore_exception_registers();
         let registers = &self.registers as *const _;
         asm!("
-            adrp x0, EARLY_STACK
-            add x0, x0, #0x2000
-            mov sp, x0
+            mov sp, {0}
 
             ldr x0, [x30, #0]
             ldr x1, [x30, #8]
@@ -159,7 +302,7 @@ impl ActiveContext {
             ldr x30, [x30, #240]
 
             eret
-        ", in("x30") registers, options(noreturn));
+        ", in(reg) stack_top.0, in("x30") registers, options(noreturn));
     }
 }
 
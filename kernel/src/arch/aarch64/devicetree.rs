@@ -0,0 +1,127 @@
+// Helpers for finding devices in the flattened device tree
+
+use byteorder::{ByteOrder, BE};
+
+use crate::vm::PhysicalAddress;
+
+/// What `#address-cells` and `#size-cells` are when a node doesn't say
+pub const DEFAULT_CELLS: (u32, u32) = (2, 1);
+
+/// How deep in the tree we bother keeping track of cell sizes
+const MAX_DEPTH: usize = 16;
+
+/// Find the first node with one of the given `compatible` strings, along with the cell sizes
+/// needed to read its `reg` and the string it matched.
+///
+/// This doesn't do any `ranges` translation, so the addresses in `reg` are only physical
+/// addresses if the node's parents map addresses one-to-one. That's true for everything QEMU's
+/// virt machine puts in the tree.
+pub fn find_compatible<'a>(
+    dt: &fdt::DeviceTree<'a>,
+    compatible: &[&str],
+) -> Option<(fdt::Node<'a>, (u32, u32), &'a str)> {
+    // the cells used by nodes at each depth, which are specified by their parent
+    let mut cells = [DEFAULT_CELLS; MAX_DEPTH + 1];
+    for node in dt.nodes() {
+        let depth = node.parents as usize;
+        if depth >= MAX_DEPTH {
+            continue;
+        }
+        cells[depth + 1] = node_cells(&node, DEFAULT_CELLS);
+        let matched = compatible_strings(&node).find(|c| compatible.contains(c));
+        if let Some(matched) = matched {
+            return Some((node, cells[depth], matched));
+        }
+    }
+    None
+}
+
+/// Iterate over the strings in the `compatible` property of `node`, most specific first.
+pub fn compatible_strings<'a>(node: &fdt::Node<'a>) -> impl Iterator<Item = &'a str> + 'a {
+    string_list(node, "compatible")
+}
+
+/// Iterate over the strings in a property made of nul-terminated strings.
+pub fn string_list<'a>(node: &fdt::Node<'a>, name: &str) -> impl Iterator<Item = &'a str> + 'a {
+    let data: &'a [u8] = node
+        .properties()
+        .find(|p| p.name == name)
+        .map(|p| p.data)
+        .unwrap_or(&[]);
+    data.split(|&b| b == 0)
+        .filter(|s| !s.is_empty())
+        .filter_map(|s| core::str::from_utf8(s).ok())
+}
+
+/// Read the `#address-cells` and `#size-cells` that `node` specifies for its children, falling
+/// back to `default` for missing properties.
+pub fn node_cells(node: &fdt::Node, default: (u32, u32)) -> (u32, u32) {
+    let mut cells = default;
+    for prop in node.properties() {
+        match prop.name {
+            "#address-cells" => cells.0 = BE::read_u32(prop.data),
+            "#size-cells" => cells.1 = BE::read_u32(prop.data),
+            _ => {}
+        }
+    }
+    cells
+}
+
+/// Iterate over the (address, size) pairs in the `reg` property of `node`, given the cell sizes
+/// of its parent.
+pub fn reg_tuples<'a>(
+    node: &fdt::Node<'a>,
+    (address_cells, size_cells): (u32, u32),
+) -> impl Iterator<Item = (PhysicalAddress, usize)> + 'a {
+    let data: &'a [u8] = node
+        .properties()
+        .find(|p| p.name == "reg")
+        .map(|p| p.data)
+        .unwrap_or(&[]);
+    let address_bytes = address_cells as usize * 4;
+    let size_bytes = size_cells as usize * 4;
+    data.chunks_exact(address_bytes + size_bytes).map(move |tuple| {
+        let address = read_cells(&tuple[..address_bytes]);
+        let size = read_cells(&tuple[address_bytes..]);
+        (PhysicalAddress(address), size)
+    })
+}
+
+fn read_cells(data: &[u8]) -> usize {
+    match data.len() {
+        0 => 0,
+        len => BE::read_uint(data, len) as usize,
+    }
+}
+
+/// Read a property that's a single cell, if `node` has it.
+pub fn u32_property(node: &fdt::Node, name: &str) -> Option<u32> {
+    node.properties()
+        .find(|p| p.name == name)
+        .filter(|p| p.data.len() >= 4)
+        .map(|p| BE::read_u32(p.data))
+}
+
+/// Find the node that other nodes refer to as `phandle`.
+pub fn find_phandle<'a>(dt: &fdt::DeviceTree<'a>, phandle: u32) -> Option<fdt::Node<'a>> {
+    dt.nodes()
+        .find(|node| u32_property(node, "phandle") == Some(phandle))
+}
+
+/// The interrupt ID of the first interrupt in the `interrupts` property of `node`.
+///
+/// This assumes the interrupt parent is the GIC, which describes an interrupt with three cells:
+/// its type (0 for shared, 1 for private), its number among interrupts of that type, and flags.
+pub fn gic_interrupt(node: &fdt::Node) -> Option<u32> {
+    let data = node.properties().find(|p| p.name == "interrupts")?.data;
+    if data.len() < 12 {
+        return None;
+    }
+    let number = BE::read_u32(&data[4..8]);
+    match BE::read_u32(&data[..4]) {
+        // shared interrupts start after the SGIs and PPIs
+        0 => Some(number + 32),
+        1 => Some(number + 16),
+        _ => None,
+    }
+}
@@ -0,0 +1,265 @@
+// The Generic Interrupt Controller
+//
+// Both GICv2 (memory mapped CPU interface) and GICv3 (system register CPU interface and per-CPU
+// redistributors) are supported; QEMU's virt machine picks one with `-M virt,gic-version=N`.
+// Every interrupt is put in group 1 so that it arrives as an IRQ rather than an FIQ.
+
+use core::arch::asm;
+
+use crate::vm::{MapFlags, PhysicalAddress, Table, VirtualAddress};
+
+use super::{devicetree, vm::KERNEL_TABLE, FRAME_SIZE};
+
+mod v2;
+mod v3;
+
+const COMPATIBLE: &[&str] = &[
+    "arm,gic-v3",
+    "arm,gic-400",
+    "arm,cortex-a15-gic",
+    "arm,cortex-a9-gic",
+];
+const GICV3_COMPATIBLE: &str = "arm,gic-v3";
+
+/// Where the GIC's registers get mapped. The distributor goes first, then the CPU interface or
+/// redistributors.
+const GIC_VIRT: VirtualAddress = VirtualAddress(0xFFFF_FF00_0100_0000);
+
+/// Interrupt IDs from here up to 1023 are special, and mean nothing is pending
+const SPECIAL_INTIDS: u32 = 1020;
+/// Software generated interrupts are 0 to 15, private peripheral interrupts 16 to 31
+pub const SGI_COUNT: u32 = 16;
+const PRIVATE_INTIDS: u32 = 32;
+
+/// The priority interrupts get unless someone says otherwise. Lower is more urgent.
+pub const DEFAULT_PRIORITY: u8 = 0xA0;
+
+static GIC: spin::Once<Gic> = spin::Once::new();
+static HANDLERS: spin::Mutex<[Option<IrqHandler>; SPECIAL_INTIDS as usize]> =
+    spin::Mutex::new([None; SPECIAL_INTIDS as usize]);
+
+/// Called with the interrupt ID when an interrupt arrives, before it's EOI'd. Runs with interrupts
+/// masked, so it should be quick.
+pub type IrqHandler = fn(u32);
+
+#[derive(Debug)]
+pub enum Error {
+    /// The interrupt ID is a special one, or more than the GIC supports
+    InvalidIntid,
+    AlreadyRegistered,
+}
+
+enum Gic {
+    V2(v2::GicV2),
+    V3(v3::GicV3),
+}
+
+/// An interrupt that has been acknowledged and needs to be EOI'd.
+#[derive(Debug)]
+pub struct Irq {
+    pub intid: u32,
+    /// What the acknowledge register said, which for GICv2 SGIs includes the sending CPU
+    raw: u32,
+}
+
+/// Who to send a software generated interrupt to.
+#[derive(Clone, Copy, Debug)]
+pub enum SgiTarget {
+    /// The CPU with the given MPIDR affinity value. GICv2 only supports 8 CPUs, and targets them
+    /// by Aff0.
+    Cpu(u64),
+    AllButSelf,
+    Current,
+}
+
+/// Find the GIC in the device tree, map it and set up the distributor and this CPU's interface.
+///
+/// # Safety
+/// Must be called once, on the boot CPU, while the kernel page tables can be modified.
+pub unsafe fn init(dt: &fdt::DeviceTree) {
+    let (node, cells, matched) = devicetree::find_compatible(dt, COMPATIBLE)
+        .expect("couldn't find an interrupt controller in the device tree");
+    let mut regs = devicetree::reg_tuples(&node, cells);
+    let (dist_phys, dist_size) = regs.next().expect("interrupt controller has no registers");
+    let (other_phys, other_size) = regs
+        .next()
+        .expect("interrupt controller is missing its cpu interface or redistributors");
+
+    let dist = map(GIC_VIRT, dist_phys, dist_size);
+    let other = map(dist + page_up(dist_size), other_phys, other_size);
+
+    let gic = if matched == GICV3_COMPATIBLE {
+        tracing::info!(dist = ?dist_phys, redist = ?other_phys, "found GICv3");
+        Gic::V3(v3::GicV3::new(dist, other, page_up(other_size)))
+    } else {
+        tracing::info!(dist = ?dist_phys, cpu = ?other_phys, "found GICv2");
+        Gic::V2(v2::GicV2::new(dist, other))
+    };
+    let gic = GIC.call_once(|| gic);
+    match gic {
+        Gic::V2(gic) => gic.init_distributor(),
+        Gic::V3(gic) => gic.init_distributor(),
+    }
+    init_cpu();
+}
+
+/// Set up the GIC for the calling CPU: its CPU interface, and for GICv3 its redistributor.
+pub fn init_cpu() {
+    match get() {
+        Gic::V2(gic) => gic.init_cpu(),
+        Gic::V3(gic) => gic.init_cpu(),
+    }
+    // the private interrupts of each CPU start off with the default priority
+    for intid in 0..PRIVATE_INTIDS {
+        set_priority(intid, DEFAULT_PRIORITY);
+    }
+}
+
+/// Call `handler` whenever `intid` fires, and enable it. SGIs and PPIs are only enabled on the
+/// calling CPU; other CPUs need to [`enable`] them for themselves.
+pub fn register_irq(intid: u32, handler: IrqHandler) -> Result<(), Error> {
+    check_intid(intid)?;
+    {
+        let mut handlers = HANDLERS.lock();
+        let slot = &mut handlers[intid as usize];
+        if slot.is_some() {
+            return Err(Error::AlreadyRegistered);
+        }
+        *slot = Some(handler);
+    }
+    set_priority(intid, DEFAULT_PRIORITY);
+    enable(intid);
+    Ok(())
+}
+
+pub fn enable(intid: u32) {
+    match get() {
+        Gic::V2(gic) => gic.set_enabled(intid, true),
+        Gic::V3(gic) => gic.set_enabled(intid, true),
+    }
+}
+
+pub fn disable(intid: u32) {
+    match get() {
+        Gic::V2(gic) => gic.set_enabled(intid, false),
+        Gic::V3(gic) => gic.set_enabled(intid, false),
+    }
+}
+
+pub fn set_priority(intid: u32, priority: u8) {
+    match get() {
+        Gic::V2(gic) => gic.set_priority(intid, priority),
+        Gic::V3(gic) => gic.set_priority(intid, priority),
+    }
+}
+
+/// Acknowledge the most urgent pending interrupt, or return `None` if there isn't one.
+pub fn ack() -> Option<Irq> {
+    let (intid, raw) = match get() {
+        Gic::V2(gic) => gic.ack(),
+        Gic::V3(gic) => gic.ack(),
+    };
+    match intid < SPECIAL_INTIDS {
+        true => Some(Irq { intid, raw }),
+        false => None,
+    }
+}
+
+/// Signal that handling of `irq` is done, so that it can fire again.
+pub fn eoi(irq: Irq) {
+    match get() {
+        Gic::V2(gic) => gic.eoi(irq.raw),
+        Gic::V3(gic) => gic.eoi(irq.raw),
+    }
+}
+
+pub fn send_sgi(intid: u32, target: SgiTarget) {
+    assert!(intid < SGI_COUNT, "{intid} isn't an SGI");
+    match get() {
+        Gic::V2(gic) => gic.send_sgi(intid, target),
+        Gic::V3(gic) => gic.send_sgi(intid, target),
+    }
+}
+
+/// Acknowledge and handle one pending interrupt, if there is one.
+pub fn handle_irq() {
+    let irq = match ack() {
+        Some(irq) => irq,
+        None => return,
+    };
+    let handler = HANDLERS.lock()[irq.intid as usize];
+    match handler {
+        Some(handler) => handler(irq.intid),
+        None => tracing::warn!(intid = irq.intid, "unexpected interrupt"),
+    }
+    eoi(irq);
+}
+
+fn get() -> &'static Gic {
+    GIC.r#try().expect("interrupt controller not initialised")
+}
+
+fn check_intid(intid: u32) -> Result<(), Error> {
+    let lines = match get() {
+        Gic::V2(gic) => gic.lines(),
+        Gic::V3(gic) => gic.lines(),
+    };
+    match intid < lines {
+        true => Ok(()),
+        false => Err(Error::InvalidIntid),
+    }
+}
+
+unsafe fn map(virt: VirtualAddress, phys: PhysicalAddress, size: usize) -> VirtualAddress {
+    KERNEL_TABLE
+        .map_to(virt, phys, page_up(size), MapFlags::KERNEL_DEVICE)
+        .unwrap();
+    virt
+}
+
+fn page_up(size: usize) -> usize {
+    (size + FRAME_SIZE - 1) / FRAME_SIZE * FRAME_SIZE
+}
+
+/// The affinity fields of MPIDR_EL1, which identify the calling CPU.
+fn current_affinity() -> u64 {
+    let mpidr: u64;
+    unsafe { asm!("mrs {0}, MPIDR_EL1", out(reg) mpidr, options(nomem, nostack)) };
+    mpidr & 0xFF_00FF_FFFF
+}
+
+unsafe fn read32(base: VirtualAddress, offset: usize) -> u32 {
+    ((base.0 + offset) as *const u32).read_volatile()
+}
+
+unsafe fn write32(base: VirtualAddress, offset: usize, value: u32) {
+    ((base.0 + offset) as *mut u32).write_volatile(value);
+}
+
+unsafe fn read64(base: VirtualAddress, offset: usize) -> u64 {
+    ((base.0 + offset) as *const u64).read_volatile()
+}
+
+unsafe fn write64(base: VirtualAddress, offset: usize, value: u64) {
+    ((base.0 + offset) as *mut u64).write_volatile(value);
+}
+
+/// Set the byte for `intid` in a register array with one byte per interrupt.
+unsafe fn write_byte_field(base: VirtualAddress, offset: usize, intid: u32, value: u8) {
+    ((base.0 + offset + intid as usize) as *mut u8).write_volatile(value);
+}
+
+/// Set or clear the bit for `intid` in a pair of set-enable/clear-enable register arrays.
+unsafe fn write_enable_bit(
+    base: VirtualAddress,
+    set_offset: usize,
+    clear_offset: usize,
+    intid: u32,
+    enabled: bool,
+) {
+    let offset = match enabled {
+        true => set_offset,
+        false => clear_offset,
+    };
+    write32(base, offset + intid as usize / 32 * 4, 1 << (intid % 32));
+}
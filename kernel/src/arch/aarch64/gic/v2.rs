@@ -0,0 +1,96 @@
+// GICv2: the distributor and CPU interface are both memory mapped
+
+use crate::vm::VirtualAddress;
+
+use super::{
+    read32, write32, write_byte_field, write_enable_bit, SgiTarget, PRIVATE_INTIDS,
+    SPECIAL_INTIDS,
+};
+
+const GICD_CTLR: usize = 0x000;
+const GICD_TYPER: usize = 0x004;
+const GICD_IGROUPR: usize = 0x080;
+const GICD_ISENABLER: usize = 0x100;
+const GICD_ICENABLER: usize = 0x180;
+const GICD_IPRIORITYR: usize = 0x400;
+const GICD_ITARGETSR: usize = 0x800;
+const GICD_SGIR: usize = 0xF00;
+
+const GICC_CTLR: usize = 0x000;
+const GICC_PMR: usize = 0x004;
+const GICC_IAR: usize = 0x00C;
+const GICC_EOIR: usize = 0x010;
+
+pub struct GicV2 {
+    dist: VirtualAddress,
+    cpu: VirtualAddress,
+}
+
+impl GicV2 {
+    pub fn new(dist: VirtualAddress, cpu: VirtualAddress) -> Self {
+        GicV2 { dist, cpu }
+    }
+
+    /// The number of interrupt IDs the distributor implements, including SGIs and PPIs.
+    pub fn lines(&self) -> u32 {
+        let typer = unsafe { read32(self.dist, GICD_TYPER) };
+        core::cmp::min(32 * ((typer & 0x1F) + 1), SPECIAL_INTIDS)
+    }
+
+    pub fn init_distributor(&self) {
+        unsafe {
+            write32(self.dist, GICD_CTLR, 0);
+            // shared interrupts start off disabled, in group 1, and routed to the boot CPU
+            for intid in (PRIVATE_INTIDS..self.lines()).step_by(32) {
+                let offset = intid as usize / 32 * 4;
+                write32(self.dist, GICD_ICENABLER + offset, 0xFFFF_FFFF);
+                write32(self.dist, GICD_IGROUPR + offset, 0xFFFF_FFFF);
+            }
+            for intid in PRIVATE_INTIDS..self.lines() {
+                write_byte_field(self.dist, GICD_ITARGETSR, intid, 0x01);
+            }
+            // bit 0 enables group 0, bit 1 enables group 1
+            write32(self.dist, GICD_CTLR, 0b11);
+        }
+    }
+
+    pub fn init_cpu(&self) {
+        unsafe {
+            // the registers for private interrupts are banked per CPU
+            write32(self.dist, GICD_ICENABLER, 0xFFFF_FFFF);
+            write32(self.dist, GICD_IGROUPR, 0xFFFF_FFFF);
+            // let everything through
+            write32(self.cpu, GICC_PMR, 0xFF);
+            // enable both groups; group 1 is signalled as IRQ
+            write32(self.cpu, GICC_CTLR, 0b11);
+        }
+    }
+
+    pub fn set_enabled(&self, intid: u32, enabled: bool) {
+        unsafe { write_enable_bit(self.dist, GICD_ISENABLER, GICD_ICENABLER, intid, enabled) };
+    }
+
+    pub fn set_priority(&self, intid: u32, priority: u8) {
+        unsafe { write_byte_field(self.dist, GICD_IPRIORITYR, intid, priority) };
+    }
+
+    /// Returns (interrupt ID, raw IAR value).
+    pub fn ack(&self) -> (u32, u32) {
+        let iar = unsafe { read32(self.cpu, GICC_IAR) };
+        (iar & 0x3FF, iar)
+    }
+
+    pub fn eoi(&self, raw: u32) {
+        unsafe { write32(self.cpu, GICC_EOIR, raw) };
+    }
+
+    pub fn send_sgi(&self, intid: u32, target: SgiTarget) {
+        // target list filter: 0 = the CPUs in the list, 1 = everyone else, 2 = ourselves
+        let (filter, list) = match target {
+            SgiTarget::Cpu(affinity) => (0, 1 << (affinity & 0x7)),
+            SgiTarget::AllButSelf => (1, 0),
+            SgiTarget::Current => (2, 0),
+        };
+        unsafe { write32(self.dist, GICD_SGIR, filter << 24 | list << 16 | intid) };
+    }
+}
@@ -0,0 +1,190 @@
+// GICv3: the distributor is memory mapped, private interrupts are configured through a
+// redistributor per CPU, and the CPU interface is a set of system registers
+
+use core::arch::asm;
+
+use crate::vm::VirtualAddress;
+
+use super::{
+    current_affinity, read32, read64, write32, write64, write_byte_field, write_enable_bit,
+    SgiTarget, PRIVATE_INTIDS, SPECIAL_INTIDS,
+};
+
+const GICD_CTLR: usize = 0x0000;
+const GICD_TYPER: usize = 0x0004;
+const GICD_IGROUPR: usize = 0x0080;
+const GICD_ISENABLER: usize = 0x0100;
+const GICD_ICENABLER: usize = 0x0180;
+const GICD_IPRIORITYR: usize = 0x0400;
+const GICD_IROUTER: usize = 0x6000;
+
+const GICD_CTLR_ENABLE_GRP1: u32 = 1 << 1;
+const GICD_CTLR_ARE: u32 = 1 << 4;
+const GICD_CTLR_RWP: u32 = 1 << 31;
+
+/// Each redistributor has two 64KiB frames: one for control and one for SGIs and PPIs
+const GICR_STRIDE: usize = 0x2_0000;
+const GICR_SGI_BASE: usize = 0x1_0000;
+const GICR_TYPER: usize = 0x0008;
+const GICR_WAKER: usize = 0x0014;
+const GICR_IGROUPR0: usize = GICR_SGI_BASE + 0x0080;
+const GICR_ISENABLER0: usize = GICR_SGI_BASE + 0x0100;
+const GICR_ICENABLER0: usize = GICR_SGI_BASE + 0x0180;
+const GICR_IPRIORITYR: usize = GICR_SGI_BASE + 0x0400;
+
+const GICR_TYPER_LAST: u64 = 1 << 4;
+const GICR_WAKER_PROCESSOR_SLEEP: u32 = 1 << 1;
+const GICR_WAKER_CHILDREN_ASLEEP: u32 = 1 << 2;
+
+pub struct GicV3 {
+    dist: VirtualAddress,
+    redists: VirtualAddress,
+    redists_size: usize,
+}
+
+impl GicV3 {
+    pub fn new(dist: VirtualAddress, redists: VirtualAddress, redists_size: usize) -> Self {
+        GicV3 {
+            dist,
+            redists,
+            redists_size,
+        }
+    }
+
+    /// The number of interrupt IDs the distributor implements, including SGIs and PPIs.
+    pub fn lines(&self) -> u32 {
+        let typer = unsafe { read32(self.dist, GICD_TYPER) };
+        core::cmp::min(32 * ((typer & 0x1F) + 1), SPECIAL_INTIDS)
+    }
+
+    pub fn init_distributor(&self) {
+        unsafe {
+            write32(self.dist, GICD_CTLR, 0);
+            self.wait_for_distributor();
+            // shared interrupts start off disabled, in group 1, and routed to the boot CPU
+            for intid in (PRIVATE_INTIDS..self.lines()).step_by(32) {
+                let offset = intid as usize / 32 * 4;
+                write32(self.dist, GICD_ICENABLER + offset, 0xFFFF_FFFF);
+                write32(self.dist, GICD_IGROUPR + offset, 0xFFFF_FFFF);
+            }
+            self.wait_for_distributor();
+            write32(self.dist, GICD_CTLR, GICD_CTLR_ARE);
+            self.wait_for_distributor();
+            // GICD_IROUTER has the affinity fields in the same places as MPIDR_EL1
+            let affinity = current_affinity();
+            for intid in PRIVATE_INTIDS..self.lines() {
+                write64(self.dist, GICD_IROUTER + intid as usize * 8, affinity);
+            }
+            write32(self.dist, GICD_CTLR, GICD_CTLR_ARE | GICD_CTLR_ENABLE_GRP1);
+            self.wait_for_distributor();
+        }
+    }
+
+    pub fn init_cpu(&self) {
+        let redist = self.redistributor();
+        unsafe {
+            // wake the redistributor up
+            let waker = read32(redist, GICR_WAKER);
+            write32(redist, GICR_WAKER, waker & !GICR_WAKER_PROCESSOR_SLEEP);
+            while read32(redist, GICR_WAKER) & GICR_WAKER_CHILDREN_ASLEEP != 0 {
+                core::hint::spin_loop();
+            }
+            write32(redist, GICR_ICENABLER0, 0xFFFF_FFFF);
+            write32(redist, GICR_IGROUPR0, 0xFFFF_FFFF);
+
+            asm!("
+                mrs {tmp}, ICC_SRE_EL1
+                orr {tmp}, {tmp}, #1
+                msr ICC_SRE_EL1, {tmp}
+                isb
+                msr ICC_PMR_EL1, {pmr}
+                msr ICC_IGRPEN1_EL1, {enable}
+                isb
+            ", tmp = out(reg) _, pmr = in(reg) 0xFFu64, enable = in(reg) 1u64, options(nostack));
+        }
+    }
+
+    pub fn set_enabled(&self, intid: u32, enabled: bool) {
+        unsafe {
+            if intid < PRIVATE_INTIDS {
+                let redist = self.redistributor();
+                write_enable_bit(redist, GICR_ISENABLER0, GICR_ICENABLER0, intid, enabled);
+            } else {
+                write_enable_bit(self.dist, GICD_ISENABLER, GICD_ICENABLER, intid, enabled);
+            }
+        }
+    }
+
+    pub fn set_priority(&self, intid: u32, priority: u8) {
+        unsafe {
+            if intid < PRIVATE_INTIDS {
+                write_byte_field(self.redistributor(), GICR_IPRIORITYR, intid, priority);
+            } else {
+                write_byte_field(self.dist, GICD_IPRIORITYR, intid, priority);
+            }
+        }
+    }
+
+    /// Returns (interrupt ID, raw IAR value).
+    pub fn ack(&self) -> (u32, u32) {
+        let iar: u64;
+        unsafe { asm!("mrs {0}, ICC_IAR1_EL1", out(reg) iar, options(nomem, nostack)) };
+        let iar = (iar & 0xFF_FFFF) as u32;
+        (iar, iar)
+    }
+
+    pub fn eoi(&self, raw: u32) {
+        unsafe { asm!("msr ICC_EOIR1_EL1, {0}", in(reg) raw as u64, options(nomem, nostack)) };
+    }
+
+    pub fn send_sgi(&self, intid: u32, target: SgiTarget) {
+        let intid = (intid as u64) << 24;
+        let value = match target {
+            SgiTarget::Cpu(affinity) => sgi_target(affinity) | intid,
+            // interrupt routing mode 1 means everyone but us
+            SgiTarget::AllButSelf => 1 << 40 | intid,
+            SgiTarget::Current => sgi_target(current_affinity()) | intid,
+        };
+        unsafe {
+            asm!("
+                msr ICC_SGI1R_EL1, {0}
+                isb
+            ", in(reg) value, options(nomem, nostack));
+        }
+    }
+
+    /// Find the redistributor belonging to the calling CPU.
+    fn redistributor(&self) -> VirtualAddress {
+        let affinity = current_affinity();
+        // GICR_TYPER has the affinity in the top 32 bits, as Aff3.Aff2.Aff1.Aff0
+        let wanted = (affinity >> 32) << 24 | (affinity & 0xFF_FFFF);
+        let mut offset = 0;
+        while offset < self.redists_size {
+            let redist = self.redists + offset;
+            let typer = unsafe { read64(redist, GICR_TYPER) };
+            if typer >> 32 == wanted {
+                return redist;
+            }
+            if typer & GICR_TYPER_LAST != 0 {
+                break;
+            }
+            offset += GICR_STRIDE;
+        }
+        panic!("no redistributor for cpu with affinity {affinity:#x}");
+    }
+
+    fn wait_for_distributor(&self) {
+        while unsafe { read32(self.dist, GICD_CTLR) } & GICD_CTLR_RWP != 0 {
+            core::hint::spin_loop();
+        }
+    }
+}
+
+/// Make the target fields of ICC_SGI1R_EL1 for the single CPU with `affinity`.
+fn sgi_target(affinity: u64) -> u64 {
+    let aff0 = affinity & 0xF;
+    let aff1 = (affinity >> 8) & 0xFF;
+    let aff2 = (affinity >> 16) & 0xFF;
+    let aff3 = (affinity >> 32) & 0xFF;
+    aff3 << 48 | aff2 << 32 | aff1 << 16 | 1 << aff0
+}
@@ -3,9 +3,24 @@ use core::arch::asm;
 use aarch64_cpu::Writeable;
 use tracing::info_span;
 
-use crate::{arch::aarch64::regs::ExceptionClass, context::Context, syscall, vm::VirtualAddress};
+use crate::{
+    arch::aarch64::{
+        regs::{AbortInfo, ExceptionClass, FaultStatus},
+        vm::USER_SPACE_END,
+    },
+    context::{sched, ActiveContextHandle},
+    syscall,
+    vm::{
+        fault::{handle_page_fault, FaultKind, PageFault},
+        Protection, VirtualAddress,
+    },
+};
 
-use super::context::{ActiveContext, Registers};
+use super::{
+    context::{ActiveContext, Registers},
+    gic,
+    usercopy::search_exception_table,
+};
 
 // the first one in the table == base address of vector table
 extern "C" {
@@ -32,7 +47,7 @@ enum InterruptSource {
 
 #[allow(dead_code)]
 #[repr(C)]
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Eq)]
 enum InterruptType {
     Synchronous = 0,
     Irq = 1,
@@ -40,38 +55,132 @@ enum InterruptType {
     SError = 3,
 }
 
+/// What a process exits with when it's killed for accessing memory it shouldn't
+const EXIT_CODE_FAULT: i32 = -1;
+
 #[no_mangle]
-extern "C" fn demux_interrupt(regs: &Registers, source: InterruptSource, ty: InterruptType) {
-    // not sure how to avoid the clone
-    let state = ActiveContext {
-        registers: regs.clone(),
-    };
-    let cx_ptr: *const Context;
-    let cx_handle = unsafe {
-        asm!("mrs {0}, TPIDR_EL0", out(reg) cx_ptr);
-        (*cx_ptr).get_handle(state)
+extern "C" fn demux_interrupt(regs: &mut Registers, source: InterruptSource, ty: InterruptType) {
+    let cx_ptr = super::context::current();
+    // if this interrupted the kernel, anything the handler does that takes another exception
+    // will clobber these, so they have to be put back before returning
+    let link: usize;
+    let spsr: u64;
+    unsafe {
+        asm!("mrs {0}, ELR_EL1", out(reg) link);
+        asm!("mrs {0}, SPSR_EL1", out(reg) spsr);
+    }
+    let mut cx_handle = match source {
+        // not sure how to avoid the clone
+        InterruptSource::LowerElAa64 => unsafe {
+            (*cx_ptr).get_handle(ActiveContext::interrupted(regs))
+        },
+        // the kernel was interrupted partway through doing something with the context, possibly
+        // while holding a handle to it, so its saved user state has to be left alone
+        _ => ActiveContextHandle(cx_ptr),
     };
 
-    let link: usize;
-    unsafe { asm!("mrs {0}, ELR_EL1", out(reg) link) };
-    let link = VirtualAddress(link);
+    let mut link = VirtualAddress(link);
     let syndrome = super::regs::ExceptionSyndrome::get();
     let span = info_span!("interrupt handler", src=?source, ?ty, cause=?syndrome.cause, ?link);
     let _guard = span.enter();
 
-    if syndrome.cause == ExceptionClass::SvcAa64 {
-        syscall::dispatch(syndrome.iss as usize, cx_handle);
-    } else {
-        let sp: u64;
-        unsafe {
-            match source {
-                InterruptSource::CurrentElSpEl0 => unreachable!("we don't configure it like that"),
-                InterruptSource::CurrentElSpElx => asm!("mov {0}, sp", out(reg) sp),
-                InterruptSource::LowerElAa64 => asm!("mrs {0}, SP_EL0", out(reg) sp),
-                InterruptSource::LowerElAa32 => unreachable!("no support for aa32"),
+    match (ty, &syndrome.cause) {
+        (InterruptType::Synchronous, ExceptionClass::SvcAa64) => {
+            syscall::dispatch(cx_handle);
+        }
+        (
+            InterruptType::Synchronous,
+            ExceptionClass::DataAbortLowerEl
+            | ExceptionClass::InstructionAbortLowerEl
+            | ExceptionClass::DataAbortSameEl,
+        ) => {
+            let abort = AbortInfo::decode(&syndrome);
+            let kind = match &abort.status {
+                FaultStatus::Translation { .. } => Some(FaultKind::NotPresent),
+                FaultStatus::Permission { .. } => Some(FaultKind::Permission),
+                // nothing we could map would fix the rest, so retrying them would just fault again
+                // forever
+                _ => None,
+            };
+            let address = VirtualAddress(abort.address);
+            let handled = match kind {
+                Some(kind) if address < USER_SPACE_END => {
+                    let fault = PageFault {
+                        address,
+                        access: match (&syndrome.cause, abort.write) {
+                            (ExceptionClass::InstructionAbortLowerEl, _) => Protection::EXEC,
+                            (_, true) => Protection::WRITE,
+                            (_, false) => Protection::READ,
+                        },
+                        kind,
+                    };
+                    handle_page_fault(&mut cx_handle, &fault).is_ok()
+                }
+                _ => false,
+            };
+            if handled {
+                core::mem::forget(cx_handle);
+            } else {
+                match source {
+                    InterruptSource::LowerElAa64 => {
+                        // there's nothing like signals to tell the process about it, so the best
+                        // we can do is kill it
+                        let sp: u64;
+                        unsafe { asm!("mrs {0}, SP_EL0", out(reg) sp) };
+                        tracing::error!(
+                            "user fault at {link:?}: {abort:?}\n\n{regs} sp: {sp:#018x}"
+                        );
+                        crate::context::exit(cx_handle, EXIT_CODE_FAULT);
+                    }
+                    _ => match search_exception_table(link) {
+                        // it was a user memory access that's allowed to fail
+                        Some(fixup) => {
+                            tracing::debug!(?fixup, "bad user memory access");
+                            link = fixup;
+                            core::mem::forget(cx_handle);
+                        }
+                        None => panic!("kernel fault at {link:?}: {abort:?}\n\n{regs}"),
+                    },
+                }
             }
         }
-        tracing::error!("unhandled exception at {link:?}!\n\n{regs} sp: {sp:#018x}");
-        core::mem::forget(cx_handle);
+        (InterruptType::Irq, _) => {
+            gic::handle_irq();
+            // interrupts are masked in the kernel except while idling, and the idle loop checks
+            // for itself, so only userspace gets preempted here, with no kernel state to worry
+            // about
+            if matches!(source, InterruptSource::LowerElAa64) && sched::take_reschedule() {
+                sched::schedule(cx_handle);
+            }
+            core::mem::forget(cx_handle);
+        }
+        _ => {
+            let sp: u64;
+            unsafe {
+                match source {
+                    InterruptSource::CurrentElSpEl0 => {
+                        unreachable!("we don't configure it like that")
+                    }
+                    InterruptSource::CurrentElSpElx => asm!("mov {0}, sp", out(reg) sp),
+                    InterruptSource::LowerElAa64 => asm!("mrs {0}, SP_EL0", out(reg) sp),
+                    InterruptSource::LowerElAa32 => unreachable!("no support for aa32"),
+                }
+            }
+            tracing::error!("unhandled exception at {link:?}!\n\n{regs} sp: {sp:#018x}");
+            core::mem::forget(cx_handle);
+        }
+    }
+
+    match source {
+        // the handler worked on the context's copy of the user state, so that's what goes back
+        InterruptSource::LowerElAa64 => {
+            let mut cx_handle = ActiveContextHandle(cx_ptr);
+            cx_handle.arch().restore(regs);
+            core::mem::forget(cx_handle);
+        }
+        _ => unsafe {
+            asm!("msr ELR_EL1, {0}", in(reg) link.0);
+            asm!("msr SPSR_EL1, {0}", in(reg) spsr);
+        },
     }
 }
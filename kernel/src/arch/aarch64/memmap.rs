@@ -0,0 +1,214 @@
+// Building the physical memory map from the device tree
+
+use byteorder::{ByteOrder, BE};
+
+use crate::vm::PhysicalAddress;
+
+use super::devicetree::{node_cells, reg_tuples};
+
+const MAX_REGIONS: usize = 32;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Region {
+    pub start: PhysicalAddress,
+    pub end: PhysicalAddress,
+}
+
+/// A set of physical address ranges, kept sorted and with no overlapping or adjacent regions.
+///
+/// This has a fixed capacity because it's built before the kernel heap is usable. It's also built
+/// before there's anywhere for logging to go, so nothing here logs; the caller can look at what's
+/// in it afterwards.
+pub struct MemoryMap {
+    regions: [Region; MAX_REGIONS],
+    len: usize,
+    /// How many regions have been ignored because there wasn't room for them
+    dropped: usize,
+}
+
+impl MemoryMap {
+    pub const fn new() -> Self {
+        MemoryMap {
+            regions: [Region {
+                start: PhysicalAddress(0),
+                end: PhysicalAddress(0),
+            }; MAX_REGIONS],
+            len: 0,
+            dropped: 0,
+        }
+    }
+
+    /// Find all usable RAM described by `dt`: every `reg` tuple of every memory node, minus
+    /// `/reserved-memory` children and the entries of the memory reservation block. The
+    /// reservations are returned too, so that they can be logged.
+    ///
+    /// Leaving out some of the RAM just wastes it, but leaving out a reservation would mean
+    /// handing it out as free memory, so this panics if there are too many reservations to keep
+    /// track of.
+    pub fn from_device_tree(dt: &fdt::DeviceTree, dtb: &[u8]) -> (Self, Self) {
+        let mut map = MemoryMap::new();
+
+        let mut root_cells = (2, 1);
+        // (depth, address cells, size cells) of the /reserved-memory node while we're inside it
+        let mut reserved: Option<(u8, u32, u32)> = None;
+        let mut reservations = MemoryMap::new();
+        for node in dt.nodes() {
+            if let Some((depth, _, _)) = reserved {
+                if node.parents <= depth {
+                    reserved = None;
+                }
+            }
+
+            match node.parents {
+                0 => root_cells = node_cells(&node, root_cells),
+                1 if node.name == "reserved-memory" => {
+                    let (address, size) = node_cells(&node, root_cells);
+                    reserved = Some((node.parents, address, size));
+                }
+                1 if is_memory_node(&node) => {
+                    for (start, size) in reg_tuples(&node, root_cells) {
+                        map.add(start, start + size);
+                    }
+                }
+                _ => {}
+            }
+
+            if let Some((depth, address_cells, size_cells)) = reserved {
+                // dynamically placed reservations don't have a reg; nobody has told us where they
+                // are so there's nothing to avoid
+                if node.parents == depth + 1 {
+                    for (start, size) in reg_tuples(&node, (address_cells, size_cells)) {
+                        reservations.add(start, start + size);
+                    }
+                }
+            }
+        }
+
+        // the memory reservation block is a list of big-endian (address, size) pairs, ending
+        // with a pair of zeroes
+        let mut offset = BE::read_u32(&dtb[16..20]) as usize;
+        loop {
+            let start = BE::read_u64(&dtb[offset..offset + 8]) as usize;
+            let size = BE::read_u64(&dtb[offset + 8..offset + 16]) as usize;
+            if start == 0 && size == 0 {
+                break;
+            }
+            reservations.add(PhysicalAddress(start), PhysicalAddress(start + size));
+            offset += 16;
+        }
+        assert_eq!(reservations.dropped(), 0, "too many memory reservations");
+
+        for region in reservations.regions() {
+            map.remove(region.start, region.end);
+        }
+        (map, reservations)
+    }
+
+    pub fn regions(&self) -> &[Region] {
+        &self.regions[..self.len]
+    }
+
+    /// How many regions have been left out because the map was full.
+    pub fn dropped(&self) -> usize {
+        self.dropped
+    }
+
+    /// The lowest and highest addresses covered by the map.
+    pub fn bounds(&self) -> Option<(PhysicalAddress, PhysicalAddress)> {
+        Some((self.regions().first()?.start, self.regions().last()?.end))
+    }
+
+    pub fn add(&mut self, start: PhysicalAddress, end: PhysicalAddress) {
+        if start >= end {
+            return;
+        }
+        let mut new = Region { start, end };
+
+        // swallow every region that overlaps or touches the new one
+        let mut idx = 0;
+        while idx < self.len {
+            let region = self.regions[idx];
+            if region.end < new.start {
+                idx += 1;
+            } else if region.start > new.end {
+                break;
+            } else {
+                new.start = core::cmp::min(new.start, region.start);
+                new.end = core::cmp::max(new.end, region.end);
+                self.delete(idx);
+            }
+        }
+        self.insert(idx, new);
+    }
+
+    pub fn remove(&mut self, start: PhysicalAddress, end: PhysicalAddress) {
+        if start >= end {
+            return;
+        }
+        let mut idx = 0;
+        while idx < self.len {
+            let region = self.regions[idx];
+            if region.end <= start || region.start >= end {
+                idx += 1;
+                continue;
+            }
+            self.delete(idx);
+            if region.start < start {
+                self.insert(
+                    idx,
+                    Region {
+                        start: region.start,
+                        end: start,
+                    },
+                );
+                idx += 1;
+            }
+            if region.end > end {
+                self.insert(
+                    idx,
+                    Region {
+                        start: end,
+                        end: region.end,
+                    },
+                );
+                idx += 1;
+            }
+        }
+    }
+
+    /// Take `size` bytes, aligned to `align`, from the end of the highest region that has room,
+    /// and remove them from the map.
+    pub fn take_from_end(&mut self, size: usize, align: usize) -> Option<PhysicalAddress> {
+        let region = self
+            .regions()
+            .iter()
+            .rev()
+            .find(|r| r.end - r.start >= size && (r.end.0 - size) / align * align >= r.start.0)?;
+        let start = PhysicalAddress((region.end.0 - size) / align * align);
+        self.remove(start, start + size);
+        Some(start)
+    }
+
+    fn insert(&mut self, idx: usize, region: Region) {
+        if self.len == MAX_REGIONS {
+            self.dropped += 1;
+            return;
+        }
+        self.regions.copy_within(idx..self.len, idx + 1);
+        self.regions[idx] = region;
+        self.len += 1;
+    }
+
+    fn delete(&mut self, idx: usize) {
+        self.regions.copy_within(idx + 1..self.len, idx);
+        self.len -= 1;
+    }
+}
+
+fn is_memory_node(node: &fdt::Node) -> bool {
+    if node.name == "memory" || node.name.starts_with("memory@") {
+        return true;
+    }
+    node.properties()
+        .any(|p| p.name == "device_type" && p.data == b"memory\0")
+}
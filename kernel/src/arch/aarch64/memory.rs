@@ -3,7 +3,7 @@ use linked_list_allocator::Heap;
 use crate::{
     arch::vm::table::{IntermediateLevel, Level1, Level2},
     memory::KERNEL_HEAP_ALLOCATOR,
-    vm::{PhysicalAddress, Table},
+    vm::{MapFlags, PhysicalAddress, Table},
 };
 
 use super::vm::{
@@ -61,7 +61,7 @@ pub fn init_early_heap(table: &mut IntermediateTable<Level0>) {
         let level2 = level1.entry_mut(l1_index).get_next_table_mut().unwrap();
         level2.insert_raw(map_frames_phys + 8192, l2_index);
         let level3 = level2.entry_mut(l2_index).get_next_table_mut().unwrap();
-        level3.map_to(KERNEL_HEAP_START, heap_phys, 4096);
+        level3.map_to(KERNEL_HEAP_START, heap_phys, 4096, MapFlags::KERNEL_DATA);
 
         // weird initialisation behaviour
         KERNEL_HEAP_ALLOCATOR.force_unlock();
@@ -71,7 +71,7 @@ pub fn init_early_heap(table: &mut IntermediateTable<Level0>) {
 
 pub fn init_main_heap(table: &mut IntermediateTable<Level0>) {
     table
-        .alloc(KERNEL_HEAP_START + 4096, 1048576 - 4096)
+        .alloc(KERNEL_HEAP_START + 4096, 1048576 - 4096, MapFlags::KERNEL_DATA)
         .unwrap();
     unsafe { KERNEL_HEAP_ALLOCATOR.lock().extend(1048576 - 4096) };
 }
@@ -6,18 +6,41 @@ use byteorder::{ByteOrder, BE};
 use tracing::info;
 
 use crate::arch::vm::{KERNEL_LOAD_PHYS, KERNEL_TABLE};
-use crate::vm::{PhysicalAddress, Table, VirtualAddress};
+use crate::vm::{MapFlags, PhysicalAddress, Table, VirtualAddress};
 use vm::table::{IntermediateLevel, IntermediateTable, Level0, Level1, Level2};
 
 pub mod context;
+mod devicetree;
+pub mod gic;
 pub mod interrupt;
 pub mod memory;
+mod memmap;
+pub mod percpu;
+mod pl011;
 pub mod platform;
+pub mod psci;
 mod regs;
+pub mod smp;
+pub mod stack;
+pub mod timer;
+pub mod usercopy;
 pub mod vm;
 
 pub const FRAME_SIZE: usize = 4096;
 
+/// Sleep until an interrupt arrives, and take it. Interrupts are masked again afterwards, like
+/// they always are in the kernel.
+pub fn wait_for_interrupt() {
+    unsafe {
+        asm!("
+            wfi
+            msr DAIFClr, #2
+            isb
+            msr DAIFSet, #2
+        ", options(nostack));
+    }
+}
+
 pub struct Arch {
     device_tree: fdt::DeviceTree<'static>,
     pub initrd: &'static [u8],
@@ -94,17 +117,26 @@ unsafe fn init() {
         kernel_remap_l1
             .insert_raw(PhysicalAddress(kernel_remap_l2_addr), 0)
             .unwrap();
-        kernel_remap_l2.insert_block(KERNEL_LOAD_PHYS, 0).unwrap();
+        // TODO: the kernel image is one block, so it can't be split into text and data with
+        // different permissions
+        kernel_remap_l2
+            .insert_block(KERNEL_LOAD_PHYS, 0, MapFlags::KERNEL_CODE | MapFlags::WRITE)
+            .unwrap();
         for i in 0..511 {
             let phys = PhysicalAddress(i * Level1::BLOCK_SIZE as usize);
-            direct_map.insert_block(phys, i).unwrap();
+            direct_map
+                .insert_block(phys, i, MapFlags::KERNEL_DATA)
+                .unwrap();
         }
         kernel_identity_l0
             .insert_raw(PhysicalAddress(kernel_identity_l1_addr), 0)
             .unwrap();
         for i in 0..511 {
             let phys = PhysicalAddress(i * Level1::BLOCK_SIZE as usize);
-            kernel_identity_l1.insert_block(phys, i).unwrap();
+            // we're executing from here while the MMU is being turned on
+            kernel_identity_l1
+                .insert_block(phys, i, MapFlags::KERNEL_CODE | MapFlags::WRITE)
+                .unwrap();
         }
 
         let mut tcr: u64;
@@ -141,12 +173,14 @@ unsafe fn init() {
             TCR_EL1::EPD0::EnableTTBR0Walks
                 + TCR_EL1::EPD1::EnableTTBR1Walks
                 + TCR_EL1::IPS::Bits_48
-                + TCR_EL1::IRGN0::NonCacheable
-                + TCR_EL1::IRGN1::NonCacheable
-                + TCR_EL1::ORGN0::NonCacheable
-                + TCR_EL1::ORGN1::NonCacheable
-                + TCR_EL1::SH0::None
-                + TCR_EL1::SH1::None
+                // table walks have to see what other CPUs have written to the tables, which
+                // might still be in their caches
+                + TCR_EL1::IRGN0::WriteBack_ReadAlloc_WriteAlloc_Cacheable
+                + TCR_EL1::IRGN1::WriteBack_ReadAlloc_WriteAlloc_Cacheable
+                + TCR_EL1::ORGN0::WriteBack_ReadAlloc_WriteAlloc_Cacheable
+                + TCR_EL1::ORGN1::WriteBack_ReadAlloc_WriteAlloc_Cacheable
+                + TCR_EL1::SH0::Inner
+                + TCR_EL1::SH1::Inner
                 + TCR_EL1::T0SZ.val(16)
                 + TCR_EL1::T1SZ.val(16)
                 + TCR_EL1::TBI0::Used
@@ -157,10 +191,12 @@ unsafe fn init() {
 
         MAIR_EL1.write(
             MAIR_EL1::Attr0_Normal_Inner::WriteBack_NonTransient_ReadWriteAlloc
-                + MAIR_EL1::Attr0_Normal_Outer::WriteBack_NonTransient_ReadWriteAlloc,
+                + MAIR_EL1::Attr0_Normal_Outer::WriteBack_NonTransient_ReadWriteAlloc
+                + MAIR_EL1::Attr1_Device::nonGathering_nonReordering_EarlyWriteAck,
         );
     }
 
+    percpu::init(0);
     memory::init_early_heap(&mut KERNEL_TABLE);
 
     let frames_virt = &memory::SPARE_FRAMES as *const _ as usize;
@@ -175,50 +211,29 @@ unsafe fn init() {
         .insert_hole(frames_phys, 4096 * 8);
 
     vm::KERNEL_TABLE
-        .map_to(VirtualAddress(0xFFFF_1000_0000_0000), dtb_phys, 4096)
+        .map_to(
+            VirtualAddress(0xFFFF_1000_0000_0000),
+            dtb_phys,
+            4096,
+            MapFlags::KERNEL_RO,
+        )
         .unwrap();
     let dt_header_bytes = core::slice::from_raw_parts(0xFFFF_1000_0000_0000 as *const _, 40);
     let dt_header = fdt::DeviceTreeHeader::new(&dt_header_bytes).unwrap();
     let dtb_size = dt_header.total_size as usize;
     vm::KERNEL_TABLE.unmap(VirtualAddress(0xFFFF_1000_0000_0000), 4096);
     vm::KERNEL_TABLE
-        .map_to(VirtualAddress(0xFFFF_1000_0000_0000), dtb_phys, dtb_size)
+        .map_to(
+            VirtualAddress(0xFFFF_1000_0000_0000),
+            dtb_phys,
+            dtb_size,
+            MapFlags::KERNEL_RO,
+        )
         .unwrap();
 
     let dtb = core::slice::from_raw_parts(0xFFFF_1000_0000_0000u64 as _, dtb_size);
     let dt = fdt::DeviceTree::new(&dtb).unwrap();
 
-    let (memory, memory_cells) = dt.find_node("/memory").unwrap();
-    assert_eq!(memory_cells.address, 2);
-    assert_eq!(memory_cells.size, 2);
-    let reg = memory.properties().find(|prop| prop.name == "reg").unwrap();
-    let start_addr = PhysicalAddress(BE::read_u64(&reg.data[0..8]) as usize);
-    let size = BE::read_u64(&reg.data[8..16]) as usize;
-    {
-        let mut frame_allocator = crate::memory::FRAME_ALLOCATOR.lock();
-        frame_allocator.insert_hole(start_addr, vm::KERNEL_LOAD_PHYS - start_addr);
-        let kernel_end = vm::KERNEL_LOAD_PHYS + 1024 * 1024 * 2;
-        frame_allocator.insert_hole(
-            vm::KERNEL_LOAD_PHYS + 1024 * 1024 * 2,
-            dtb_phys - kernel_end,
-        );
-        let dtb_end = dtb_phys + dtb_size;
-        frame_allocator.insert_hole(dtb_end, start_addr + size - dtb_end);
-    }
-
-    KERNEL_TABLE
-        .map_to(
-            VirtualAddress(0xFFFF_FF00_0000_0000),
-            PhysicalAddress(0x0000_0000_0900_0000),
-            4096,
-        )
-        .unwrap();
-    tracing::subscriber::set_global_default(crate::tracing::PutcharSubscriber::new()).unwrap();
-    let span = tracing::info_span!("kernel entry point");
-    let _guard = span.enter();
-    info!("Hello, universe!");
-    interrupt::init_interrupts();
-
     let (chosen, _chosen_cells) = dt.find_node("/chosen").unwrap();
     let initrd_start_prop = chosen
         .properties()
@@ -232,6 +247,66 @@ unsafe fn init() {
     let initrd_end = BE::read_uint(initrd_end_prop.data, initrd_end_prop.data.len()) as usize;
     let initrd_size = initrd_end - initrd_start;
     let initrd_start = PhysicalAddress(initrd_start);
+    if let Some(bootargs) = chosen.properties().find(|p| p.name == "bootargs") {
+        if let Ok(bootargs) = core::str::from_utf8(bootargs.data) {
+            crate::cmdline::init(bootargs);
+        }
+    }
+
+    extern "C" {
+        static __end: u8;
+    }
+    let kernel_size = core::ptr::addr_of!(__end) as usize - vm::KERNEL_OFFSET;
+
+    let (mut memory_map, reservations) = memmap::MemoryMap::from_device_tree(&dt, dtb);
+    if let (Some(limit), Some((memory_start, _))) =
+        (crate::cmdline::get().mem_limit, memory_map.bounds())
+    {
+        let limit = PhysicalAddress(memory_start.0.saturating_add(limit));
+        memory_map.remove(limit, PhysicalAddress(usize::MAX));
+    }
+    let (memory_start, memory_end) = memory_map
+        .bounds()
+        .expect("device tree doesn't describe any memory");
+    memory_map.remove(vm::KERNEL_LOAD_PHYS, vm::KERNEL_LOAD_PHYS + kernel_size);
+    memory_map.remove(dtb_phys, dtb_phys + dtb_size);
+    memory_map.remove(initrd_start, initrd_start + initrd_size);
+    {
+        let frame_table_size = crate::memory::frame::table_size(memory_start, memory_end);
+        let frame_table_phys = memory_map
+            .take_from_end(frame_table_size, FRAME_SIZE)
+            .expect("no room for the frame table");
+        crate::memory::frame::init(memory_start, memory_end, frame_table_phys);
+
+        let mut frame_allocator = crate::memory::FRAME_ALLOCATOR.lock();
+        for region in memory_map.regions() {
+            frame_allocator.insert_hole(region.start, region.end - region.start);
+        }
+    }
+
+    tracing::subscriber::set_global_default(crate::tracing::PutcharSubscriber::new()).unwrap();
+    pl011::init(&dt);
+    let span = tracing::info_span!("kernel entry point");
+    let _guard = span.enter();
+    info!("Hello, universe!");
+    // as early as it can be with logging, so that panics can power off
+    psci::init(&dt);
+    interrupt::init_interrupts();
+    vm::asid::init();
+    gic::init(&dt);
+    timer::init();
+    pl011::init_irq();
+
+    for region in reservations.regions() {
+        tracing::debug!(start = ?region.start, end = ?region.end, "reserved memory");
+    }
+    for region in memory_map.regions() {
+        tracing::debug!(start = ?region.start, end = ?region.end, "usable memory");
+    }
+    let dropped = memory_map.dropped();
+    if dropped > 0 {
+        tracing::warn!(dropped, "too many memory regions; ignored some RAM");
+    }
     tracing::debug!(
         "found initrd at {:x?}, size {:x?}",
         initrd_start,
@@ -242,6 +317,7 @@ unsafe fn init() {
             VirtualAddress(0xFFFF_1000_4000_0000),
             initrd_start,
             initrd_size,
+            MapFlags::KERNEL_RO,
         )
         .unwrap();
     let initrd =
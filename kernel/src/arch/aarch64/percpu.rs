@@ -0,0 +1,84 @@
+// Data that each CPU has its own copy of
+//
+// TPIDR_EL1 points at the calling CPU's entry in `CPUS`, which is set up before anything else on
+// that CPU runs Rust code that could need it. Userspace can't see TPIDR_EL1, unlike TPIDR_EL0,
+// which belongs to whatever process is running.
+
+use core::{
+    arch::asm,
+    ptr::null_mut,
+    sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, AtomicUsize, Ordering},
+};
+
+use crate::context::Context;
+
+/// GICv2 can't send interrupts to more CPUs than this, so it'll do
+pub const MAX_CPUS: usize = 8;
+
+#[allow(clippy::declare_interior_mutable_const)]
+const OFFLINE: PerCpu = PerCpu {
+    mpidr: AtomicU64::new(0),
+    current: AtomicPtr::new(null_mut()),
+    asid: AtomicU64::new(0),
+    flush_pending: AtomicBool::new(true),
+    current_span: AtomicU64::new(0),
+};
+static CPUS: [PerCpu; MAX_CPUS] = [OFFLINE; MAX_CPUS];
+/// How many CPUs have called [`init`], which are `CPUS[..ONLINE]`
+static ONLINE: AtomicUsize = AtomicUsize::new(0);
+
+pub struct PerCpu {
+    /// The affinity fields of MPIDR_EL1, which is how the GIC and PSCI identify the CPU
+    pub mpidr: AtomicU64,
+    /// The context that's active on the CPU, if there is one
+    pub current: AtomicPtr<Context>,
+    /// The [`Asid`](super::vm::asid::Asid) of the address space in TTBR0
+    pub asid: AtomicU64,
+    /// Set when the CPU's TLB might hold entries that no ASID accounts for, which need flushing
+    /// before the next address space is trusted. Other CPUs set this, e.g. on ASID rollover.
+    pub flush_pending: AtomicBool,
+    /// The id of the tracing span the CPU is in, or 0 if it isn't in one
+    pub current_span: AtomicU64,
+}
+
+/// Point TPIDR_EL1 at the data for CPU number `id`. CPUs have to come online in order, starting
+/// with the boot CPU as 0.
+///
+/// # Safety
+/// Must be called once on each CPU, before anything uses [`this`].
+pub unsafe fn init(id: usize) {
+    let cpu = &CPUS[id];
+    let mpidr: u64;
+    asm!("mrs {0}, MPIDR_EL1", out(reg) mpidr, options(nomem, nostack, preserves_flags));
+    cpu.mpidr.store(mpidr & 0xFF_00FF_FFFF, Ordering::Relaxed);
+    asm!("msr TPIDR_EL1, {0}", in(reg) cpu, options(nomem, nostack, preserves_flags));
+    ONLINE.fetch_add(1, Ordering::Release);
+}
+
+/// The calling CPU's data.
+pub fn this() -> &'static PerCpu {
+    let cpu: *const PerCpu;
+    unsafe { asm!("mrs {0}, TPIDR_EL1", out(reg) cpu, options(nomem, nostack, preserves_flags)) };
+    unsafe { &*cpu }
+}
+
+/// The number of the calling CPU.
+pub fn id() -> usize {
+    // safety: this() is always somewhere in CPUS
+    unsafe { (this() as *const PerCpu).offset_from(CPUS.as_ptr()) as usize }
+}
+
+/// The data for CPU number `id`.
+pub fn get(id: usize) -> &'static PerCpu {
+    &CPUS[id]
+}
+
+/// The number of CPUs that are up and running, which are numbered from 0.
+pub fn online() -> usize {
+    ONLINE.load(Ordering::Acquire)
+}
+
+/// The data of every CPU that's up and running.
+pub fn all() -> &'static [PerCpu] {
+    &CPUS[..online()]
+}
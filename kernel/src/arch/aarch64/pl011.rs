@@ -0,0 +1,161 @@
+// The ARM PL011 UART, which is the console
+//
+// Output is polled: each byte waits for room in the transmit FIFO. Input comes in on an interrupt,
+// which fires when the receive FIFO is getting full or has had something sitting in it for a
+// while, and everything in the FIFO is handed to the console then.
+
+use crate::vm::{MapFlags, Table, VirtualAddress};
+
+use super::{devicetree, gic, vm::KERNEL_TABLE, FRAME_SIZE};
+
+const COMPATIBLE: &[&str] = &["arm,pl011"];
+
+/// Where the UART's registers get mapped
+const UART_VIRT: VirtualAddress = VirtualAddress(0xFFFF_FF00_0000_0000);
+
+const BAUD_RATE: u32 = 115_200;
+/// What the UART's reference clock is assumed to run at if the device tree doesn't say, which is
+/// what QEMU uses
+const DEFAULT_CLOCK: u32 = 24_000_000;
+
+const UARTDR: usize = 0x000;
+const UARTFR: usize = 0x018;
+const UARTIBRD: usize = 0x024;
+const UARTFBRD: usize = 0x028;
+const UARTLCR_H: usize = 0x02C;
+const UARTCR: usize = 0x030;
+const UARTIMSC: usize = 0x038;
+const UARTICR: usize = 0x044;
+
+const FR_BUSY: u32 = 1 << 3;
+const FR_RXFE: u32 = 1 << 4;
+const FR_TXFF: u32 = 1 << 5;
+
+/// 8 bits per character, no parity and one stop bit
+const LCR_H_WLEN_8: u32 = 0b11 << 5;
+const LCR_H_FEN: u32 = 1 << 4;
+
+const CR_UARTEN: u32 = 1 << 0;
+const CR_TXE: u32 = 1 << 8;
+const CR_RXE: u32 = 1 << 9;
+
+/// The receive interrupt, and the receive timeout interrupt for when there's something in the FIFO
+/// but not enough to set off the other one
+const INT_RX: u32 = 1 << 4;
+const INT_RT: u32 = 1 << 6;
+const INT_ALL: u32 = 0x7FF;
+
+/// The receive error bits that come along with each byte in UARTDR
+const DR_ERRORS: u32 = 0xF << 8;
+
+/// The UART's interrupt ID, once it's found
+static INTID: spin::Once<u32> = spin::Once::new();
+
+/// Find the UART in the device tree, map it and set it up, and make it the console. Input doesn't
+/// arrive until [`init_irq`] has been called too. Without a UART, the kernel carries on without a
+/// console.
+///
+/// # Safety
+/// Must be called once, on the boot CPU, while the kernel page tables can be modified.
+pub unsafe fn init(dt: &fdt::DeviceTree) {
+    // the warnings below go nowhere, but there's no reason not to boot without a console
+    let (node, cells, _) = match devicetree::find_compatible(dt, COMPATIBLE) {
+        Some(found) => found,
+        None => {
+            tracing::warn!("no PL011 UART in the device tree, so there's no console");
+            return;
+        }
+    };
+    let (phys, size) = match devicetree::reg_tuples(&node, cells).next() {
+        Some(reg) => reg,
+        None => {
+            tracing::warn!("UART has no registers, so there's no console");
+            return;
+        }
+    };
+    let size = (size + FRAME_SIZE - 1) / FRAME_SIZE * FRAME_SIZE;
+    if KERNEL_TABLE
+        .map_to(UART_VIRT, phys, size, MapFlags::KERNEL_DEVICE)
+        .is_err()
+    {
+        tracing::warn!(?phys, "couldn't map the UART, so there's no console");
+        return;
+    }
+
+    // the first clock is the reference clock, which the baud rate is divided down from
+    let clock = devicetree::u32_property(&node, "clocks")
+        .and_then(|phandle| devicetree::find_phandle(dt, phandle))
+        .and_then(|clock| devicetree::u32_property(&clock, "clock-frequency"))
+        .unwrap_or(DEFAULT_CLOCK);
+    configure(clock, BAUD_RATE);
+    if let Some(intid) = devicetree::gic_interrupt(&node) {
+        INTID.call_once(|| intid);
+    }
+
+    *crate::console::WRITER.lock() = crate::console::Writer(putchar);
+    tracing::info!(?phys, clock, baud = BAUD_RATE, "found PL011 UART");
+}
+
+/// Start taking input, now that the GIC is set up.
+pub fn init_irq() {
+    let intid = match INTID.r#try() {
+        Some(&intid) => intid,
+        None => {
+            tracing::warn!("UART has no interrupt, so there's no console input");
+            return;
+        }
+    };
+    gic::register_irq(intid, handle_irq).unwrap();
+    unsafe { write(UARTIMSC, INT_RX | INT_RT) };
+}
+
+/// Set the UART up for `baud` 8N1, given its reference clock, with both FIFOs on and every
+/// interrupt masked.
+unsafe fn configure(clock: u32, baud: u32) {
+    // the line control register can only be changed while the UART is off and idle
+    write(UARTCR, 0);
+    while read(UARTFR) & FR_BUSY != 0 {
+        core::hint::spin_loop();
+    }
+    // the divisor is clock / (16 * baud), with a 6-bit fraction, so 64 times that, rounded
+    let divisor = (4 * clock as u64 + baud as u64 / 2) / baud as u64;
+    write(UARTIBRD, (divisor >> 6) as u32);
+    write(UARTFBRD, (divisor & 0x3F) as u32);
+    // writing the line control register is what makes the new baud rate take effect
+    write(UARTLCR_H, LCR_H_WLEN_8 | LCR_H_FEN);
+    write(UARTIMSC, 0);
+    write(UARTICR, INT_ALL);
+    write(UARTCR, CR_UARTEN | CR_TXE | CR_RXE);
+}
+
+/// Send a byte, waiting for room in the transmit FIFO first.
+pub fn putchar(c: u8) {
+    unsafe {
+        while read(UARTFR) & FR_TXFF != 0 {
+            core::hint::spin_loop();
+        }
+        write(UARTDR, c as u32);
+    }
+}
+
+fn handle_irq(_intid: u32) {
+    // reading the FIFO until it's empty clears the interrupts
+    crate::console::receive(core::iter::from_fn(|| unsafe {
+        while read(UARTFR) & FR_RXFE == 0 {
+            let data = read(UARTDR);
+            // bytes that arrived broken are no use to anyone
+            if data & DR_ERRORS == 0 {
+                return Some(data as u8);
+            }
+        }
+        None
+    }));
+}
+
+unsafe fn read(offset: usize) -> u32 {
+    ((UART_VIRT.0 + offset) as *const u32).read_volatile()
+}
+
+unsafe fn write(offset: usize, value: u32) {
+    ((UART_VIRT.0 + offset) as *mut u32).write_volatile(value);
+}
@@ -1,9 +1,49 @@
-// PSCI based platform control
+// Platform control: powering the machine off and resetting it, through PSCI
 
-/// system must be a psci system else UB
-pub unsafe fn shutdown() -> ! {
-    let x: usize = 0x84000008;
+use super::psci;
+
+/// Power the machine off. If that can't be done, the calling CPU stops instead.
+pub fn shutdown() -> ! {
+    let err = psci::system_off();
+    tracing::error!(?err, "couldn't power off");
+    halt()
+}
+
+/// Power the machine off like [`shutdown`], except that in test mode the emulator is told to exit
+/// with `code`, so whatever ran the tests can tell whether they passed. That's done through
+/// semihosting, which QEMU only provides with `-semihosting`.
+pub fn exit(code: i32) -> ! {
+    if crate::cmdline::get().test_mode {
+        semihosting_exit(code);
+    }
+    shutdown()
+}
+
+/// Semihosting's SYS_EXIT, reporting a normal exit with `code` as the status. Only returns if the
+/// emulator didn't exit.
+fn semihosting_exit(code: i32) {
+    const SYS_EXIT: usize = 0x18;
+    const ADP_STOPPED_APPLICATION_EXIT: usize = 0x2_0026;
+    let block = [ADP_STOPPED_APPLICATION_EXIT, code as usize];
     unsafe {
-        core::arch::asm!("hvc #0", in("x0") x, options(noreturn));
+        core::arch::asm!(
+            "hlt #0xf000",
+            inout("x0") SYS_EXIT => _,
+            in("x1") block.as_ptr(),
+            options(nostack, readonly),
+        )
+    };
+}
+
+/// Reset the machine. If that can't be done, the calling CPU stops instead.
+pub fn reboot() -> ! {
+    let err = psci::system_reset();
+    tracing::error!(?err, "couldn't reset");
+    halt()
+}
+
+fn halt() -> ! {
+    loop {
+        unsafe { core::arch::asm!("wfi", options(nomem, nostack)) };
     }
 }
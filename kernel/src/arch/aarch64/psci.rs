@@ -0,0 +1,288 @@
+// The Power State Coordination Interface, which firmware (or the hypervisor, or QEMU) provides for
+// turning CPUs and the whole machine on and off
+//
+// Calls go through `hvc` or `smc`, whichever the /psci node in the device tree says. Without one,
+// every call fails with `Error::NotSupported`. The 64-bit versions of calls are used wherever
+// there's a choice.
+
+use core::arch::asm;
+
+use crate::vm::PhysicalAddress;
+
+use super::devicetree;
+
+/// Anything compatible with PSCI 0.2 has the standard function numbers
+const COMPATIBLE: &[&str] = &["arm,psci-0.2", "arm,psci-1.0"];
+
+static PSCI: spin::Once<Psci> = spin::Once::new();
+
+struct Psci {
+    conduit: Conduit,
+    version: Version,
+    /// Whether CPU_SUSPEND takes power states in the extended format
+    extended_power_state: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Conduit {
+    Hvc,
+    Smc,
+}
+
+/// The PSCI calls there are, numbered as they're passed in x0.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u32)]
+pub enum Function {
+    Version = 0x8400_0000,
+    CpuSuspend = 0xC400_0001,
+    CpuOff = 0x8400_0002,
+    CpuOn = 0xC400_0003,
+    AffinityInfo = 0xC400_0004,
+    SystemOff = 0x8400_0008,
+    SystemReset = 0x8400_0009,
+    /// Only from PSCI 1.0
+    Features = 0x8400_000A,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Version {
+    pub major: u16,
+    pub minor: u16,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error {
+    NotSupported,
+    InvalidParameters,
+    Denied,
+    AlreadyOn,
+    OnPending,
+    InternalFailure,
+    NotPresent,
+    Disabled,
+    InvalidAddress,
+    /// An error code this doesn't know about
+    Unknown(i32),
+}
+
+impl Error {
+    fn from_code(code: i32) -> Self {
+        match code {
+            -1 => Error::NotSupported,
+            -2 => Error::InvalidParameters,
+            -3 => Error::Denied,
+            -4 => Error::AlreadyOn,
+            -5 => Error::OnPending,
+            -6 => Error::InternalFailure,
+            -7 => Error::NotPresent,
+            -8 => Error::Disabled,
+            -9 => Error::InvalidAddress,
+            code => Error::Unknown(code),
+        }
+    }
+}
+
+/// What [`affinity_info`] says about a CPU.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AffinityState {
+    On,
+    Off,
+    /// It's been turned on, but hasn't got going yet
+    OnPending,
+}
+
+/// Find out how to make PSCI calls from the device tree, and what the PSCI implementation can do.
+pub fn init(dt: &fdt::DeviceTree) {
+    let node = match dt.find_node("/psci") {
+        Some((node, _)) => node,
+        None => {
+            tracing::warn!("no PSCI in the device tree");
+            return;
+        }
+    };
+    // PSCI 0.1 has its function numbers in the device tree instead, and isn't worth bothering with
+    if !devicetree::compatible_strings(&node).any(|c| COMPATIBLE.contains(&c)) {
+        tracing::warn!("PSCI is too old");
+        return;
+    }
+    let method = devicetree::string_list(&node, "method").next();
+    let conduit = match method {
+        Some("hvc") => Conduit::Hvc,
+        Some("smc") => Conduit::Smc,
+        method => {
+            tracing::warn!(?method, "no usable PSCI conduit");
+            return;
+        }
+    };
+    let raw = call(conduit, Function::Version, 0, 0, 0) as u32;
+    let version = Version {
+        major: (raw >> 16) as u16,
+        minor: raw as u16,
+    };
+    let mut psci = Psci {
+        conduit,
+        version,
+        extended_power_state: false,
+    };
+    if version.major >= 1 {
+        let flags = query_features(&psci, Function::CpuSuspend).unwrap_or(0);
+        psci.extended_power_state = flags & (1 << 1) != 0;
+    }
+    tracing::info!(
+        ?conduit,
+        major = version.major,
+        minor = version.minor,
+        "found PSCI"
+    );
+    PSCI.call_once(|| psci);
+}
+
+/// The version of PSCI that's implemented.
+// nothing calls this or the others allowed to be dead yet, but they're part of the interface
+#[allow(dead_code)]
+pub fn version() -> Result<Version, Error> {
+    Ok(get()?.version)
+}
+
+/// Whether `function` is implemented, and if so, the flags that say how it behaves. Only PSCI 1.0
+/// and up can be asked; before that, only CPU_OFF, CPU_ON, SYSTEM_OFF and SYSTEM_RESET are
+/// certain to be there.
+#[allow(dead_code)]
+pub fn features(function: Function) -> Result<u32, Error> {
+    query_features(get()?, function)
+}
+
+fn query_features(psci: &Psci, function: Function) -> Result<u32, Error> {
+    if psci.version.major < 1 {
+        return Err(Error::NotSupported);
+    }
+    match call(psci.conduit, Function::Features, function as usize, 0, 0) {
+        code if code < 0 => Err(Error::from_code(code)),
+        flags => Ok(flags as u32),
+    }
+}
+
+/// Power the machine off. This only returns if it couldn't be done.
+pub fn system_off() -> Error {
+    match get() {
+        Ok(psci) => Error::from_code(call(psci.conduit, Function::SystemOff, 0, 0, 0)),
+        Err(e) => e,
+    }
+}
+
+/// Reset the machine. This only returns if it couldn't be done.
+pub fn system_reset() -> Error {
+    match get() {
+        Ok(psci) => Error::from_code(call(psci.conduit, Function::SystemReset, 0, 0, 0)),
+        Err(e) => e,
+    }
+}
+
+/// Start the CPU with the given MPIDR affinity at physical address `entry`, with the MMU off and
+/// `context_id` in x0.
+///
+/// # Safety
+/// `entry` must be code that can run like that, and cope with being handed `context_id`.
+pub unsafe fn cpu_on(mpidr: u64, entry: PhysicalAddress, context_id: usize) -> Result<(), Error> {
+    let psci = get()?;
+    result(call(
+        psci.conduit,
+        Function::CpuOn,
+        mpidr as usize,
+        entry.0,
+        context_id,
+    ))
+}
+
+/// Power the calling CPU off. This only returns if it couldn't be done; otherwise the CPU only
+/// runs again if something calls [`cpu_on`] for it, which starts it from scratch.
+#[allow(dead_code)]
+pub fn cpu_off() -> Error {
+    match get() {
+        Ok(psci) => Error::from_code(call(psci.conduit, Function::CpuOff, 0, 0, 0)),
+        Err(e) => e,
+    }
+}
+
+/// Put the calling CPU into the low power state `power_state` until an interrupt arrives. Only
+/// standby states are allowed, which lose nothing and return here when they're done; power down
+/// states would lose the CPU's state and start it again somewhere else.
+#[allow(dead_code)]
+pub fn cpu_suspend(power_state: u32) -> Result<(), Error> {
+    let psci = get()?;
+    // the state type bit says whether it's a power down state, and is in a different place in
+    // each format
+    let power_down = match psci.extended_power_state {
+        true => 1 << 30,
+        false => 1 << 16,
+    };
+    if power_state & power_down != 0 {
+        return Err(Error::InvalidParameters);
+    }
+    result(call(
+        psci.conduit,
+        Function::CpuSuspend,
+        power_state as usize,
+        0,
+        0,
+    ))
+}
+
+/// Find out whether the CPU with the given MPIDR affinity is on. With a `lowest_level` above 0,
+/// this is about the whole group of CPUs with the affinity fields above that level, which is on if
+/// any of them is.
+pub fn affinity_info(mpidr: u64, lowest_level: u32) -> Result<AffinityState, Error> {
+    let psci = get()?;
+    let code = call(
+        psci.conduit,
+        Function::AffinityInfo,
+        mpidr as usize,
+        lowest_level as usize,
+        0,
+    );
+    match code {
+        0 => Ok(AffinityState::On),
+        1 => Ok(AffinityState::Off),
+        2 => Ok(AffinityState::OnPending),
+        code => Err(Error::from_code(code)),
+    }
+}
+
+fn get() -> Result<&'static Psci, Error> {
+    PSCI.r#try().ok_or(Error::NotSupported)
+}
+
+fn result(code: i32) -> Result<(), Error> {
+    match code {
+        0 => Ok(()),
+        code => Err(Error::from_code(code)),
+    }
+}
+
+fn call(conduit: Conduit, function: Function, a: usize, b: usize, c: usize) -> i32 {
+    let ret: usize;
+    // not nomem, since whatever gets started might look at memory we've just written
+    unsafe {
+        match conduit {
+            Conduit::Hvc => asm!(
+                "hvc #0",
+                inout("x0") function as usize => ret,
+                inout("x1") a => _,
+                inout("x2") b => _,
+                inout("x3") c => _,
+                clobber_abi("C"),
+                options(nostack),
+            ),
+            Conduit::Smc => asm!(
+                "smc #0",
+                inout("x0") function as usize => ret,
+                inout("x1") a => _,
+                inout("x2") b => _,
+                inout("x3") c => _,
+                clobber_abi("C"),
+                options(nostack),
+            ),
+        }
+    }
+    ret as i32
+}
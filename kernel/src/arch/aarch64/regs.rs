@@ -65,3 +65,51 @@ impl ExceptionSyndrome {
         }
     }
 }
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum FaultStatus {
+    AddressSize { level: u8 },
+    Translation { level: u8 },
+    AccessFlag { level: u8 },
+    Permission { level: u8 },
+    Other(u8),
+}
+
+/// The interesting parts of the syndrome of an instruction or data abort.
+#[derive(Debug)]
+pub struct AbortInfo {
+    /// The faulting virtual address, from FAR_EL1
+    pub address: usize,
+    pub status: FaultStatus,
+    /// Whether the access was a write. Always false for instruction aborts
+    pub write: bool,
+}
+
+impl AbortInfo {
+    pub fn decode(syndrome: &ExceptionSyndrome) -> Self {
+        let address: usize;
+        unsafe { core::arch::asm!("mrs {0}, FAR_EL1", out(reg) address) };
+        // DFSC and IFSC have the same encoding for the bits we care about
+        let fsc = (syndrome.iss & 0b11_1111) as u8;
+        let level = fsc & 0b11;
+        let status = match fsc >> 2 {
+            0b0000 => FaultStatus::AddressSize { level },
+            0b0001 => FaultStatus::Translation { level },
+            0b0010 => FaultStatus::AccessFlag { level },
+            0b0011 => FaultStatus::Permission { level },
+            _ => FaultStatus::Other(fsc),
+        };
+        let write = match syndrome.cause {
+            ExceptionClass::DataAbortLowerEl | ExceptionClass::DataAbortSameEl => {
+                syndrome.iss & (1 << 6) != 0
+            }
+            _ => false,
+        };
+
+        AbortInfo {
+            address,
+            status,
+            write,
+        }
+    }
+}
@@ -0,0 +1,248 @@
+// Starting the other CPUs
+//
+// The boot CPU starts each CPU under /cpus in the device tree with PSCI CPU_ON, one at a time. A
+// CPU comes up at the physical address of `secondary_entry` with the MMU off, so it's handed what
+// it needs to turn the MMU on the same way the boot CPU did in SECONDARY_BOOT. The boot CPU waits
+// for each CPU to come online before reusing that for the next one.
+//
+// Once it's up, a CPU runs its idle thread, and the scheduler gives it work from then on. CPUs
+// poke each other with an SGI when they queue something for one that's idling.
+
+use core::{arch::asm, sync::atomic::Ordering};
+
+use alloc::vec::Vec;
+
+use crate::{
+    context::sched,
+    vm::{PhysicalAddress, VirtualAddress},
+};
+
+use super::{
+    devicetree, gic, interrupt,
+    percpu::{self, MAX_CPUS},
+    psci, timer,
+    vm::{KERNEL_IDENTITY_L0, KERNEL_LOAD_PHYS, KERNEL_OFFSET},
+    Arch,
+};
+
+/// The SGI that tells a CPU to go and look at its run queue
+const RESCHEDULE_SGI: u32 = 0;
+/// How long a CPU gets to come online before it's given up on
+const START_TIMEOUT_MS: u64 = 1000;
+
+/// What a CPU needs to get from `secondary_entry` to `secondary_main`. The offsets are hardcoded
+/// in `secondary_entry`.
+#[repr(C)]
+struct SecondaryBoot {
+    mair: u64,
+    tcr: u64,
+    ttbr0: u64,
+    ttbr1: u64,
+    sctlr: u64,
+    /// The top of the CPU's idle thread's stack, which it uses until it starts the idle thread
+    stack: u64,
+    /// The virtual address of `secondary_main`
+    main: u64,
+}
+
+static mut SECONDARY_BOOT: SecondaryBoot = SecondaryBoot {
+    mair: 0,
+    tcr: 0,
+    ttbr0: 0,
+    ttbr1: 0,
+    sctlr: 0,
+    stack: 0,
+    main: 0,
+};
+
+/// Start every other CPU in the device tree that PSCI can start, and return once they're all
+/// online or have been given up on.
+pub fn start_secondaries(arch: &Arch) {
+    gic::register_irq(RESCHEDULE_SGI, handle_reschedule).unwrap();
+    unsafe { init_secondary_boot() };
+    let this = percpu::this().mpidr.load(Ordering::Relaxed);
+    // made before the CPU it's for is started, and kept if the CPU won't start
+    let mut stack = None;
+    for mpidr in cpus(&arch.device_tree) {
+        if mpidr == this {
+            continue;
+        }
+        let id = percpu::online();
+        if id == MAX_CPUS {
+            tracing::warn!(mpidr, "too many cpus; ignoring the rest");
+            break;
+        }
+        let top = *stack.get_or_insert_with(|| sched::create_idle_thread(id));
+        match start(id, mpidr, top) {
+            Ok(()) => stack = None,
+            Err(StartError::Refused(err)) => {
+                tracing::warn!(mpidr, ?err, "PSCI wouldn't start cpu");
+            }
+            // if PSCI says it's off, it isn't going to turn up late, so its number can be reused
+            Err(StartError::TimedOut)
+                if psci::affinity_info(mpidr, 0) == Ok(psci::AffinityState::Off) =>
+            {
+                tracing::warn!(mpidr, "cpu didn't come online, and has turned off again");
+            }
+            Err(StartError::TimedOut) => {
+                // it could still turn up later as CPU number `id`, so nothing else can be it
+                tracing::warn!(mpidr, "cpu didn't come online; not starting any more");
+                break;
+            }
+        }
+    }
+    tracing::info!(count = percpu::online(), "cpus online");
+}
+
+/// Make a CPU that's idling look at its run queue.
+pub fn kick(cpu: usize) {
+    let mpidr = percpu::get(cpu).mpidr.load(Ordering::Relaxed);
+    gic::send_sgi(RESCHEDULE_SGI, gic::SgiTarget::Cpu(mpidr));
+}
+
+enum StartError {
+    Refused(psci::Error),
+    TimedOut,
+}
+
+/// Start the CPU with affinity `mpidr` as CPU number `id`, on the stack whose top is `stack`, and
+/// wait for it to come online.
+fn start(id: usize, mpidr: u64, stack: VirtualAddress) -> Result<(), StartError> {
+    unsafe {
+        SECONDARY_BOOT.stack = stack.0 as u64;
+        // the CPU reads it with the MMU off, so straight from memory
+        clean_to_poc(
+            core::ptr::addr_of!(SECONDARY_BOOT) as usize,
+            core::mem::size_of::<SecondaryBoot>(),
+        );
+    }
+    let entry = PhysicalAddress(image_phys(secondary_entry as usize));
+    // safety: secondary_entry is made for this, and it's handed the CPU number it expects
+    unsafe { psci::cpu_on(mpidr, entry, id) }.map_err(StartError::Refused)?;
+    let deadline = timer::counter() + timer::frequency() * START_TIMEOUT_MS / 1000;
+    while percpu::online() == id {
+        if timer::counter() > deadline {
+            return Err(StartError::TimedOut);
+        }
+        core::hint::spin_loop();
+    }
+    Ok(())
+}
+
+/// The MPIDR affinity of every CPU under /cpus that's started with PSCI.
+fn cpus(dt: &fdt::DeviceTree) -> Vec<u64> {
+    let mut cpus = Vec::new();
+    // the cells /cpus gives its children, while we're inside it
+    let mut cpus_cells = None;
+    for node in dt.nodes() {
+        match node.parents {
+            1 => {
+                cpus_cells = (node.name == "cpus")
+                    .then(|| devicetree::node_cells(&node, devicetree::DEFAULT_CELLS));
+                continue;
+            }
+            2 => {}
+            _ => continue,
+        }
+        let cells = match cpus_cells {
+            Some(cells) => cells,
+            None => continue,
+        };
+        if devicetree::string_list(&node, "device_type").next() != Some("cpu") {
+            continue;
+        }
+        // the address of a cpu is its MPIDR
+        let mpidr = match devicetree::reg_tuples(&node, cells).next() {
+            Some((address, _)) => address.0 as u64,
+            None => continue,
+        };
+        match devicetree::string_list(&node, "enable-method").next() {
+            Some("psci") => cpus.push(mpidr),
+            method => tracing::warn!(mpidr, ?method, "don't know how to start cpu"),
+        }
+    }
+    cpus
+}
+
+/// Fill in everything in SECONDARY_BOOT that's the same for every CPU, copying the boot CPU.
+unsafe fn init_secondary_boot() {
+    let (mair, tcr, ttbr1, sctlr): (u64, u64, u64, u64);
+    asm!("
+        mrs {0}, MAIR_EL1
+        mrs {1}, TCR_EL1
+        mrs {2}, TTBR1_EL1
+        mrs {3}, SCTLR_EL1
+    ", out(reg) mair, out(reg) tcr, out(reg) ttbr1, out(reg) sctlr,
+        options(nomem, nostack, preserves_flags));
+    SECONDARY_BOOT = SecondaryBoot {
+        mair,
+        tcr,
+        // the identity map is still there from boot, and the CPU is running from it when the MMU
+        // comes on
+        ttbr0: image_phys(core::ptr::addr_of!(KERNEL_IDENTITY_L0) as usize) as u64,
+        ttbr1,
+        sctlr,
+        stack: 0,
+        main: secondary_main as usize as u64,
+    };
+}
+
+/// Where something in the kernel image is in physical memory.
+fn image_phys(virt: usize) -> usize {
+    virt - KERNEL_OFFSET + KERNEL_LOAD_PHYS.0
+}
+
+/// Write `len` bytes at `virt` back to main memory, where things with the MMU off can see it.
+unsafe fn clean_to_poc(virt: usize, len: usize) {
+    // the smallest cache line size there could be
+    const LINE: usize = 16;
+    let mut line = virt & !(LINE - 1);
+    while line < virt + len {
+        asm!("dc civac, {0}", in(reg) line, options(nostack, preserves_flags));
+        line += LINE;
+    }
+    asm!("dsb sy", options(nostack, preserves_flags));
+}
+
+/// Where a CPU started by PSCI comes in, at its physical address, with the MMU off and its number
+/// in x0.
+#[naked]
+unsafe extern "C" fn secondary_entry() {
+    asm!("
+        adrp x1, {boot}
+        add x1, x1, :lo12:{boot}
+        ldp x2, x3, [x1, #0]
+        msr MAIR_EL1, x2
+        msr TCR_EL1, x3
+        ldp x2, x3, [x1, #16]
+        msr TTBR0_EL1, x2
+        msr TTBR1_EL1, x3
+        // don't trap on FP/SIMD register access
+        mov x2, #(3 << 20)
+        msr CPACR_EL1, x2
+        // whatever the TLB has from before is junk
+        tlbi vmalle1
+        dsb nsh
+        isb
+        ldp x2, x3, [x1, #32]
+        ldr x4, [x1, #48]
+        msr SCTLR_EL1, x2
+        isb
+        mov sp, x3
+        br x4
+    ", boot = sym SECONDARY_BOOT, options(noreturn));
+}
+
+extern "C" fn secondary_main(id: usize) -> ! {
+    unsafe { percpu::init(id) };
+    interrupt::init_interrupts();
+    gic::init_cpu();
+    timer::init_cpu();
+    gic::enable(RESCHEDULE_SGI);
+    tracing::debug!(id, "cpu online");
+    sched::start()
+}
+
+fn handle_reschedule(_intid: u32) {
+    sched::request_reschedule();
+}
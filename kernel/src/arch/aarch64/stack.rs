@@ -0,0 +1,105 @@
+// Kernel stacks, one per context, which the exception vectors push onto when the context traps
+// into the kernel
+//
+// Each stack lives in its own slot in the terabyte from KERNEL_STACKS_START. The top half of a
+// slot is the stack and the bottom half is never mapped, so running off the end of a stack faults
+// instead of trampling whatever is below it. Slots are aligned to their size, so the exception
+// vectors can tell that the stack pointer has gone into the guard just by looking at bit
+// STACK_SIZE_SHIFT of it (see interrupts.S, which hardcodes these numbers).
+
+use alloc::vec::Vec;
+
+use crate::{
+    memory::{frame::FrameOwner, FRAME_ALLOCATOR},
+    vm::{MapFlags, Table, VirtualAddress},
+};
+
+use super::{vm::KERNEL_TABLE, FRAME_SIZE};
+
+/// Every address in the slots starts with 0xFFFF20 in its top 24 bits, which the exception
+/// vectors rely on to tell a context's stack from the boot stack or anything else
+const KERNEL_STACKS_START: VirtualAddress = VirtualAddress(0xFFFF_2000_0000_0000);
+const KERNEL_STACKS_SIZE: usize = 1 << 40;
+const STACK_SIZE_SHIFT: usize = 14;
+pub const STACK_SIZE: usize = 1 << STACK_SIZE_SHIFT;
+/// A stack and its guard
+const SLOT_SIZE: usize = 2 * STACK_SIZE;
+const MAX_SLOTS: usize = KERNEL_STACKS_SIZE / SLOT_SIZE;
+
+static SLOTS: spin::Mutex<Slots> = spin::Mutex::new(Slots {
+    next: 0,
+    free: Vec::new(),
+});
+
+struct Slots {
+    /// The lowest slot that has never been used
+    next: usize,
+    /// Slots that were used and then freed
+    free: Vec<usize>,
+}
+
+pub struct KernelStack {
+    slot: usize,
+}
+
+impl KernelStack {
+    /// Allocate and map a new stack.
+    pub fn new() -> Self {
+        // held while mapping too, since neighbouring stacks share page tables and other CPUs could
+        // be making or freeing them
+        let mut slots = SLOTS.lock();
+        let slot = slots.free.pop().unwrap_or_else(|| {
+            slots.next += 1;
+            slots.next - 1
+        });
+        assert!(slot < MAX_SLOTS, "out of kernel stack slots");
+        let stack = KernelStack { slot };
+        let mut page = stack.bottom();
+        while page < stack.top() {
+            let frame = FRAME_ALLOCATOR.lock().alloc();
+            crate::memory::frame::set_owner(frame, FrameOwner::Kernel);
+            unsafe { KERNEL_TABLE.map_to(page, frame, FRAME_SIZE, MapFlags::KERNEL_DATA) }
+                .expect("kernel stack slot already mapped");
+            page += FRAME_SIZE;
+        }
+        stack
+    }
+
+    /// The initial stack pointer, which is just past the end of the stack.
+    pub fn top(&self) -> VirtualAddress {
+        self.bottom() + STACK_SIZE
+    }
+
+    fn bottom(&self) -> VirtualAddress {
+        slot_bottom(self.slot)
+    }
+}
+
+impl Drop for KernelStack {
+    /// Unmap and free the stack. It mustn't be in use, which is why contexts that exit are freed
+    /// by the reaper rather than on their way out.
+    fn drop(&mut self) {
+        let mut slots = SLOTS.lock();
+        let mut page = self.bottom();
+        for _ in 0..STACK_SIZE / FRAME_SIZE {
+            unsafe {
+                let (frame, _) = KERNEL_TABLE.translate(page).unwrap();
+                KERNEL_TABLE.unmap(page, FRAME_SIZE);
+                crate::memory::frame::put(frame);
+            }
+            page += FRAME_SIZE;
+        }
+        slots.free.push(self.slot);
+    }
+}
+
+fn slot_bottom(slot: usize) -> VirtualAddress {
+    KERNEL_STACKS_START + slot * SLOT_SIZE + (SLOT_SIZE - STACK_SIZE)
+}
+
+/// Called by the exception vectors, on an emergency stack, when an exception is taken with the
+/// stack pointer in (or about to go into) the guard below a kernel stack.
+#[no_mangle]
+extern "C" fn handle_kernel_stack_overflow(sp: usize, far: usize, elr: usize) -> ! {
+    panic!("kernel stack overflow: sp = {sp:#x}, fault address = {far:#x}, pc = {elr:#x}");
+}
@@ -0,0 +1,61 @@
+// The ARM generic timer
+
+use core::arch::asm;
+
+use super::gic;
+
+/// The interrupt ID of the EL1 virtual timer, which is PPI 11
+pub const VIRTUAL_TIMER_INTID: u32 = 27;
+
+/// How long a context gets to run before it's preempted
+const TIME_SLICE_MS: u64 = 10;
+
+pub fn init() {
+    gic::register_irq(VIRTUAL_TIMER_INTID, handle_timer).unwrap();
+}
+
+/// Enable the timer's interrupt on a CPU other than the boot CPU, which [`init`] did.
+pub fn init_cpu() {
+    gic::enable(VIRTUAL_TIMER_INTID);
+}
+
+/// Read the physical count of the generic timer, which ticks at a fixed frequency from boot.
+pub fn counter() -> u64 {
+    let count: u64;
+    unsafe { asm!("isb; mrs {0}, CNTPCT_EL0", out(reg) count, options(nomem, nostack)) };
+    count
+}
+
+/// The number of timer ticks per second.
+pub fn frequency() -> u64 {
+    let freq: u64;
+    unsafe { asm!("mrs {0}, CNTFRQ_EL0", out(reg) freq, options(nomem, nostack)) };
+    freq
+}
+
+/// Make the virtual timer fire an interrupt after `ticks` ticks.
+pub fn arm(ticks: u64) {
+    unsafe {
+        asm!("
+            msr CNTV_TVAL_EL0, {0}
+            msr CNTV_CTL_EL0, {1}
+            isb
+        ", in(reg) ticks, in(reg) 1u64, options(nomem, nostack));
+    }
+}
+
+/// Start a new time slice for whatever is about to run.
+pub fn start_slice() {
+    arm(frequency() * TIME_SLICE_MS / 1000);
+}
+
+/// Stop the timer from firing.
+pub fn stop() {
+    unsafe { asm!("msr CNTV_CTL_EL0, xzr", "isb", options(nomem, nostack)) };
+}
+
+fn handle_timer(_intid: u32) {
+    // the interrupt is level triggered, so it has to be silenced until the next slice starts
+    stop();
+    crate::context::sched::request_reschedule();
+}
@@ -0,0 +1,56 @@
+// The arch side of accessing user memory: copy loops that can recover from faults, and the table
+// that says how
+
+use crate::vm::VirtualAddress;
+
+#[repr(C)]
+struct ExceptionTableEntry {
+    insn: usize,
+    fixup: usize,
+}
+
+extern "C" {
+    fn copy_user(dst: *mut u8, src: *const u8, len: usize) -> usize;
+    fn strncpy_user(dst: *mut u8, src: *const u8, len: usize) -> isize;
+
+    static __ex_table_start: ExceptionTableEntry;
+    static __ex_table_end: ExceptionTableEntry;
+}
+
+/// If the kernel faulted at `pc` while accessing user memory, get where it should carry on
+/// instead.
+pub fn search_exception_table(pc: VirtualAddress) -> Option<VirtualAddress> {
+    let table = unsafe {
+        let start = &__ex_table_start as *const ExceptionTableEntry;
+        let end = &__ex_table_end as *const ExceptionTableEntry;
+        core::slice::from_raw_parts(start, end.offset_from(start) as usize)
+    };
+    table
+        .iter()
+        .find(|entry| entry.insn == pc.0)
+        .map(|entry| VirtualAddress(entry.fixup))
+}
+
+/// Copy `len` bytes, either of which may be user memory. Returns Err if something faulted,
+/// in which case only some of the bytes were copied.
+///
+/// # Safety
+/// Any kernel memory in either range must be valid to access.
+pub unsafe fn copy(dst: *mut u8, src: *const u8, len: usize) -> Result<(), ()> {
+    match copy_user(dst, src, len) {
+        0 => Ok(()),
+        _ => Err(()),
+    }
+}
+
+/// Copy a nul-terminated string of at most `len` bytes from user memory. Returns the length of
+/// the string, or `len` if it didn't end in time.
+///
+/// # Safety
+/// `dst` must be valid for `len` bytes of writes.
+pub unsafe fn strncpy(dst: *mut u8, src: *const u8, len: usize) -> Result<usize, ()> {
+    match strncpy_user(dst, src, len) {
+        -1 => Err(()),
+        copied => Ok(copied as usize),
+    }
+}
@@ -0,0 +1,163 @@
+// Address space identifiers, which tag the TLB entries for non-global (i.e. user) pages so that
+// they don't have to be thrown away whenever TTBR0 changes
+//
+// ASIDs are handed out in generations. When they run out, the whole TLB is flushed and a new
+// generation starts; every address space notices that its ASID is from an old generation the
+// next time it's switched to, and gets a new one.
+//
+// Each CPU's TLB only holds what that CPU has walked, so after a rollover a CPU can carry on with
+// an old ASID until its next switch, and flushes its own TLB then. Switching address spaces never
+// touches another CPU's TLB. Changing the page tables does, since other CPUs can have walked them
+// too: unmapped and protected pages are invalidated on every CPU, `release` in table.rs throws away
+// everything under the active ASID everywhere, and so does giving an ASID back.
+
+use core::{arch::asm, sync::atomic::Ordering};
+
+use aarch64_cpu::{
+    registers::{ID_AA64MMFR0_EL1, TCR_EL1},
+    ReadWriteable, Readable,
+};
+
+use crate::arch::percpu;
+
+/// Enough for 16-bit ASIDs; with 8-bit ones only the start is used
+const MAX_ASIDS: usize = 1 << 16;
+/// Where the generation starts in an [`Asid`]
+const GENERATION_SHIFT: u32 = 16;
+
+static ALLOCATOR: spin::Mutex<Allocator> = spin::Mutex::new(Allocator {
+    generation: 1,
+    count: 1 << 8,
+    used: [0; MAX_ASIDS / 64],
+    next: 1,
+});
+
+/// An ASID, along with the generation it was allocated in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Asid(u64);
+
+impl Asid {
+    /// Never valid, so the first switch to an address space gives it a real ASID.
+    pub const NONE: Asid = Asid(0);
+
+    pub fn asid(self) -> u16 {
+        self.0 as u16
+    }
+
+    fn generation(self) -> u64 {
+        self.0 >> GENERATION_SHIFT
+    }
+}
+
+struct Allocator {
+    generation: u64,
+    /// How many ASIDs the hardware has
+    count: usize,
+    /// One bit per ASID, set if it's been handed out in this generation
+    used: [u64; MAX_ASIDS / 64],
+    /// Where to start looking for a free ASID
+    next: usize,
+}
+
+impl Allocator {
+    fn is_used(&self, asid: usize) -> bool {
+        self.used[asid / 64] & (1 << (asid % 64)) != 0
+    }
+
+    fn set_used(&mut self, asid: usize, used: bool) {
+        match used {
+            true => self.used[asid / 64] |= 1 << (asid % 64),
+            false => self.used[asid / 64] &= !(1 << (asid % 64)),
+        }
+    }
+
+    /// Find a free ASID in this generation. 0 is never handed out; it's what TTBR0 has when
+    /// there's no address space to speak of.
+    fn find_free(&mut self) -> Option<usize> {
+        let asid = (self.next..self.count)
+            .chain(1..self.next)
+            .find(|&asid| !self.is_used(asid))?;
+        self.set_used(asid, true);
+        self.next = asid + 1;
+        Some(asid)
+    }
+}
+
+/// Use 16-bit ASIDs if the CPU has them.
+///
+/// # Safety
+/// Must be called before any address space is switched to.
+pub unsafe fn init() {
+    // 0b0010 means 16 bits, and 0b0000 means 8
+    if ID_AA64MMFR0_EL1.read(ID_AA64MMFR0_EL1::ASIDBits) == 0b0010 {
+        TCR_EL1.modify(TCR_EL1::AS::ASID16Bits);
+        asm!("isb");
+        ALLOCATOR.lock().count = MAX_ASIDS;
+    }
+    tracing::debug!(count = ALLOCATOR.lock().count, "ASIDs available");
+}
+
+/// Make sure `asid` is valid in the current generation, giving it a new ASID if it isn't.
+/// Returns true if this CPU's TLB has to be flushed once the address space is in TTBR0.
+pub fn assign(asid: &mut Asid) -> bool {
+    let mut allocator = ALLOCATOR.lock();
+    // every CPU starts off with this set, since the TLB might still have global entries from the
+    // identity map used during boot
+    let flush = percpu::this().flush_pending.swap(false, Ordering::Relaxed);
+    if asid.generation() == allocator.generation {
+        return flush;
+    }
+    let new = match allocator.find_free() {
+        Some(new) => new,
+        None => {
+            // out of ASIDs, so start again. whatever was cached under the old ones is junk now,
+            // on every CPU
+            allocator.generation += 1;
+            allocator.used.fill(0);
+            allocator.next = 1;
+            for cpu in percpu::all() {
+                cpu.flush_pending.store(true, Ordering::Relaxed);
+            }
+            percpu::this().flush_pending.store(false, Ordering::Relaxed);
+            tracing::debug!(generation = allocator.generation, "ASID rollover");
+            let new = allocator.find_free().unwrap();
+            *asid = Asid(allocator.generation << GENERATION_SHIFT | new as u64);
+            return true;
+        }
+    };
+    *asid = Asid(allocator.generation << GENERATION_SHIFT | new as u64);
+    flush
+}
+
+/// Give back an ASID whose address space is going away, throwing away everything the TLB has
+/// cached for it.
+pub fn release(asid: Asid) {
+    let mut allocator = ALLOCATOR.lock();
+    if asid.generation() != allocator.generation {
+        // it's already been forgotten about
+        return;
+    }
+    invalidate(asid);
+    allocator.set_used(asid.asid() as usize, false);
+}
+
+/// Throw away every TLB entry tagged with `asid`, on every CPU.
+pub(super) fn invalidate(asid: Asid) {
+    unsafe {
+        asm!("
+            dsb ishst
+            tlbi aside1is, {0}
+            dsb ish
+            isb
+        ", in(reg) (asid.asid() as u64) << 48);
+    }
+}
+
+/// The ASID of the address space that's currently in TTBR0 on this CPU.
+pub fn active() -> Asid {
+    Asid(percpu::this().asid.load(Ordering::Relaxed))
+}
+
+pub(super) fn set_active(asid: Asid) {
+    percpu::this().asid.store(asid.0, Ordering::Relaxed);
+}
@@ -2,40 +2,94 @@ use core::arch::asm;
 use core::mem::MaybeUninit;
 
 use crate::vm::{PhysicalAddress, Table, VirtualAddress};
+use asid::Asid;
 use table::{IntermediateTable, Level0, Level1, Level2};
 
 pub type TopLevelTable = IntermediateTable<Level0>;
 
+/// Make `phys` the active user table, tagged with `asid`, which is given a fresh ASID if it needs
+/// one.
+///
 /// # Safety
 /// Good luck
-pub unsafe fn switch_table(phys: PhysicalAddress) {
+pub unsafe fn switch_table(phys: PhysicalAddress, asid: &mut Asid) {
+    let flush = asid::assign(asid);
+    let ttbr = phys.0 as u64 | (asid.asid() as u64) << 48;
     asm!("
         msr TTBR0_EL1, {0}
-        tlbi vmalle1
         isb
-    ", in(reg) phys.0);
+    ", in(reg) ttbr);
+    if flush {
+        asm!("
+            dsb nshst
+            tlbi vmalle1
+            dsb nsh
+            isb
+        ");
+    }
+    asid::set_active(*asid);
 }
 
 pub fn init_user_table(phys: PhysicalAddress) {
     unsafe {
-        KERNEL_TABLE.map_to(USER_TABLE_SCRATCH, phys, 4096).unwrap();
-        let new_table_uninit =
-            unsafe { &mut *(USER_TABLE_SCRATCH.0 as *mut MaybeUninit<TopLevelTable>) };
+        // through the direct map, rather than a fixed scratch mapping, so that other CPUs can be
+        // doing the same thing at the same time
+        let new_table_uninit = &mut *(phys_to_virt(phys).0 as *mut MaybeUninit<TopLevelTable>);
         let init: &mut TopLevelTable = Table::clear(new_table_uninit);
         // recursive mapping!
-        unsafe {
-            init.insert_raw(get_current_user_table(), 511).unwrap();
-            asm!(
-                "
-                tlbi vmalle1
-                isb
-            "
-            );
-        }
-        KERNEL_TABLE.unmap(USER_TABLE_SCRATCH, 4096);
+        init.insert_raw(phys, 511).unwrap();
+        // nothing can have cached anything from a table that's never been used, but the walker
+        // has to see it once it's switched to
+        asm!("dsb ishst", options(nostack, preserves_flags));
     }
 }
 
+/// Get a user table that isn't necessarily the active one, through the direct map.
+///
+/// # Safety
+/// `phys` must be the top level of a user table that nothing else is modifying.
+pub unsafe fn table_at(phys: PhysicalAddress) -> &'static mut TopLevelTable {
+    &mut *(phys_to_virt(phys).0 as *mut TopLevelTable)
+}
+
+/// Throw away any cached translation for the page containing `virt`, on every CPU, after
+/// changing the active user table or the kernel table. This includes cached entries from every
+/// level of the walk, not just the last.
+pub fn invalidate_page(virt: VirtualAddress) {
+    // the operand is bits 55:12 of the address, and the ASID in the top 16 bits. the ASID doesn't
+    // matter for kernel pages, which are global
+    let asid = asid::active().asid() as usize;
+    let operand = (virt.0 >> 12) & 0xFFF_FFFF_FFFF | asid << 48;
+    unsafe {
+        asm!("
+            dsb ishst
+            tlbi vae1is, {0}
+            dsb ish
+            isb
+        ", in(reg) operand);
+    }
+}
+
+/// Like [`invalidate_page`], for every page in `virt..virt + size`, with a single barrier at the
+/// end rather than one per page.
+pub fn invalidate_range(virt: VirtualAddress, size: usize) {
+    let asid = asid::active().asid() as usize;
+    unsafe { asm!("dsb ishst", options(nostack, preserves_flags)) };
+    for page in (virt.0 / 4096 * 4096..virt.0 + size).step_by(4096) {
+        let operand = (page >> 12) & 0xFFF_FFFF_FFFF | asid << 48;
+        unsafe { asm!("tlbi vae1is, {0}", in(reg) operand, options(nostack, preserves_flags)) };
+    }
+    unsafe { asm!("dsb ish", "isb", options(nostack, preserves_flags)) };
+}
+
+/// Get the address at which `phys` can be accessed through the direct map.
+pub fn phys_to_virt(phys: PhysicalAddress) -> VirtualAddress {
+    let mask = 0xFFFF_FF80_0000_0000;
+    let phys = phys.0;
+    assert!(phys & mask == 0, "too much physical memory");
+    VirtualAddress(phys | mask)
+}
+
 pub fn get_current_user_table() -> PhysicalAddress {
     let table_phys: usize;
     unsafe {
@@ -46,6 +100,7 @@ pub fn get_current_user_table() -> PhysicalAddress {
     PhysicalAddress(table_phys & 0x0000_FFFF_FFFF_FFFE)
 }
 
+pub mod asid;
 mod fmt;
 pub(super) mod table;
 
@@ -53,7 +108,9 @@ pub(super) const KERNEL_OFFSET: usize = 0xFFFF_0000_0000_0000;
 pub(super) const KERNEL_LOAD_PHYS: PhysicalAddress = PhysicalAddress(0x4020_0000);
 pub(super) const KERNEL_HEAP_START: VirtualAddress = VirtualAddress(0xFFFF_1000_8000_0000);
 pub const USER_TABLE: VirtualAddress = VirtualAddress(0x0000_FFFF_FFFF_F000);
-const USER_TABLE_SCRATCH: VirtualAddress = VirtualAddress(0xFFFF_0000_1000_0000);
+/// The last level 0 entry of a user table is the recursive mapping, so user memory has to stop
+/// before it
+pub const USER_SPACE_END: VirtualAddress = VirtualAddress(0x0000_FF80_0000_0000);
 
 #[no_mangle]
 pub static mut KERNEL_TABLE: IntermediateTable<Level0> = IntermediateTable::new();
@@ -1,6 +1,7 @@
 use crate::{
     fmt::ForceLowerHex,
-    vm::{PhysicalAddress, Table, VirtualAddress},
+    memory::frame::FrameOwner,
+    vm::{MapFlags, PhysicalAddress, Table, VirtualAddress},
 };
 use alloc::boxed::Box;
 use core::{
@@ -10,7 +11,7 @@ use core::{
     mem::MaybeUninit,
 };
 
-use super::fmt::debug_page_or_block;
+use super::{asid, fmt::debug_page_or_block, invalidate_page, invalidate_range, phys_to_virt};
 
 macro_rules! set_bit {
     ($value:expr, $bit:expr, $bool:expr) => {
@@ -21,11 +22,37 @@ macro_rules! set_bit {
     };
 }
 
-fn phys_to_virt(phys: PhysicalAddress) -> VirtualAddress {
-    let mask = 0xFFFF_FF80_0000_0000;
-    let phys = phys.0;
-    assert!(phys & mask == 0, "too much physical memory");
-    VirtualAddress(phys | mask)
+/// Descriptor bits shared by blocks and pages
+const LEAF_ATTRIBUTE_MASK: u64 = (0b11 << 53) | (0b11_1111_1111 << 2);
+const ATTR_INDEX_NORMAL: u64 = 0;
+const ATTR_INDEX_DEVICE: u64 = 1;
+const INNER_SHAREABLE: u64 = 0b11;
+
+/// Writable and executable user memory is not allowed.
+fn check_flags(flags: MapFlags) -> Result<(), ()> {
+    match flags.contains(MapFlags::USER | MapFlags::WRITE | MapFlags::EXEC) {
+        true => Err(()),
+        false => Ok(()),
+    }
+}
+
+/// Encode `flags` as the attribute bits of a page or block descriptor. There's no way to make a
+/// valid mapping unreadable, so [`MapFlags::READ`] is implied.
+fn leaf_attributes(flags: MapFlags) -> u64 {
+    let mut value = 0;
+    let user = flags.contains(MapFlags::USER);
+    let exec = flags.contains(MapFlags::EXEC);
+    set_bit!(&mut value, 54, !(user && exec));
+    set_bit!(&mut value, 53, user || !exec);
+    set_bit!(&mut value, 11, !flags.contains(MapFlags::GLOBAL));
+    set_bit!(&mut value, 10, true);
+    set_bit!(&mut value, 7, !flags.contains(MapFlags::WRITE));
+    set_bit!(&mut value, 6, user);
+    match flags.contains(MapFlags::DEVICE) {
+        true => value |= ATTR_INDEX_DEVICE << 2,
+        false => value |= (INNER_SHAREABLE << 8) | (ATTR_INDEX_NORMAL << 2),
+    }
+    value
 }
 
 pub trait IntermediateLevel: Copy {
@@ -127,18 +154,24 @@ impl<L: IntermediateLevel> IntermediateTable<L> {
         self.entries[idx] = entry;
     }
 
-    pub fn insert_block(&mut self, block: PhysicalAddress, idx: usize) -> Result<(), ()> {
+    pub fn insert_block(
+        &mut self,
+        block: PhysicalAddress,
+        idx: usize,
+        flags: MapFlags,
+    ) -> Result<(), ()> {
         if self.entries[idx].is_valid() {
             return Err(());
         }
+        check_flags(flags)?;
         unsafe {
-            self.force_insert_block(block, idx);
+            self.force_insert_block(block, idx, flags);
         }
         Ok(())
     }
 
-    pub unsafe fn force_insert_block(&mut self, phys: PhysicalAddress, idx: usize) {
-        self.entries[idx] = IntermediateTableEntry::new_block(phys);
+    pub unsafe fn force_insert_block(&mut self, phys: PhysicalAddress, idx: usize, flags: MapFlags) {
+        self.entries[idx] = IntermediateTableEntry::new_block(phys, flags);
     }
 
     pub fn entry(&self, idx: usize) -> &IntermediateTableEntry<L> {
@@ -222,33 +255,36 @@ impl<L: IntermediateLevel> Table for IntermediateTable<L> {
         mut virt: VirtualAddress,
         mut phys: PhysicalAddress,
         mut size: usize,
+        flags: MapFlags,
     ) -> Result<(), ()> {
-        let start_idx = ((virt.0 as u64 >> L::VIRT_SHIFT_AMT) & 0x1FF) as usize;
-        let end_idx = (((virt.0 + size - 1) as u64 >> L::VIRT_SHIFT_AMT) & 0x1FF) as usize;
-        for i in start_idx..=end_idx {
-            let entry = &mut self.entries[i];
-            match entry.get_next_table_mut() {
-                Some(next_table) => {
-                    return next_table.map_to(
-                        virt,
-                        phys,
-                        core::cmp::min(size, L::BLOCK_SIZE as usize),
-                    );
+        check_flags(flags)?;
+        let block_size = L::BLOCK_SIZE as usize;
+        while size > 0 {
+            let idx = ((virt.0 as u64 >> L::VIRT_SHIFT_AMT) & 0x1FF) as usize;
+            // the part of the range that falls under this entry
+            let chunk = core::cmp::min(size, block_size - (virt.0 & (block_size - 1)));
+            if !self.entries[idx].is_table() {
+                // something else (a block) is already mapped here
+                if self.entries[idx].is_valid() {
+                    return Err(());
                 }
-                None => {
-                    let frame_phys = crate::memory::FRAME_ALLOCATOR.lock().alloc();
-                    unsafe {
-                        self.insert_raw(frame_phys, i)?;
-                    }
-                    let next_table_uninit =
-                        unsafe { &mut *(phys_to_virt(frame_phys).0 as *mut MaybeUninit<L::Next>) };
-                    let next_table: &mut L::Next = Table::clear(next_table_uninit);
-                    next_table.map_to(virt, phys, size)?;
+                let frame_phys = crate::memory::FRAME_ALLOCATOR.lock().alloc();
+                crate::memory::frame::set_owner(frame_phys, FrameOwner::PageTable);
+                // the table has to be empty before anything can walk it, and that includes other
+                // CPUs, so clear it and make sure that's visible before it goes in
+                let next_table_uninit =
+                    unsafe { &mut *(phys_to_virt(frame_phys).0 as *mut MaybeUninit<L::Next>) };
+                <L::Next as Table>::clear(next_table_uninit);
+                unsafe {
+                    asm!("dsb ishst", options(nostack, preserves_flags));
+                    self.insert_raw(frame_phys, idx)?;
                 }
             }
-            virt += L::BLOCK_SIZE as usize;
-            phys += L::BLOCK_SIZE as usize;
-            size = size.saturating_sub(L::BLOCK_SIZE as usize);
+            let next_table = self.entries[idx].get_next_table_mut().unwrap();
+            next_table.map_to(virt, phys, chunk, flags)?;
+            virt += chunk;
+            phys += chunk;
+            size -= chunk;
         }
         if L::IS_TOP_LEVEL {
             unsafe {
@@ -263,21 +299,80 @@ impl<L: IntermediateLevel> Table for IntermediateTable<L> {
         Ok(())
     }
 
-    fn unmap(&mut self, virt: VirtualAddress, size: usize) {
-        let starting_idx = ((virt.0 as u64 >> L::VIRT_SHIFT_AMT) & 0x1FF) as usize;
+    fn protect(&mut self, mut virt: VirtualAddress, mut size: usize, flags: MapFlags) {
+        let (start, total_size) = (virt, size);
         let block_size = L::BLOCK_SIZE as usize;
-        let mut entries_needed = (size + block_size - 1) / block_size;
-        if starting_idx + entries_needed > 512 {
-            entries_needed = 512 - starting_idx;
+        while size > 0 {
+            let idx = ((virt.0 as u64 >> L::VIRT_SHIFT_AMT) & 0x1FF) as usize;
+            let chunk = core::cmp::min(size, block_size - (virt.0 & (block_size - 1)));
+            let entry = &mut self.entries[idx];
+            if let Some(block) = entry.block_address() {
+                *entry = IntermediateTableEntry::new_block(block, flags);
+            } else if let Some(next_table) = entry.get_next_table_mut() {
+                next_table.protect(virt, chunk, flags);
+            }
+            virt += chunk;
+            size -= chunk;
+        }
+        if L::IS_TOP_LEVEL {
+            invalidate_range(start, total_size);
         }
-        for i in 0..entries_needed {
-            let idx = i + starting_idx;
+    }
+
+    fn remap(
+        &mut self,
+        virt: VirtualAddress,
+        phys: PhysicalAddress,
+        flags: MapFlags,
+    ) -> Result<(), ()> {
+        check_flags(flags)?;
+        let idx = ((virt.0 as u64 >> L::VIRT_SHIFT_AMT) & 0x1FF) as usize;
+        // blocks are only used for the kernel's own mappings, which never move
+        let next_table = self.entries[idx].get_next_table_mut().ok_or(())?;
+        next_table.remap(virt, phys, flags)
+    }
+
+    fn translate(&self, virt: VirtualAddress) -> Option<(PhysicalAddress, MapFlags)> {
+        let idx = ((virt.0 as u64 >> L::VIRT_SHIFT_AMT) & 0x1FF) as usize;
+        let entry = &self.entries[idx];
+        match entry.block_address() {
+            Some(block) => {
+                // blocks have their attributes in the same places as pages
+                let flags = Level3TableEntry { value: entry.value }.get_flags();
+                Some((block + (virt.0 & (L::BLOCK_SIZE as usize - 1)), flags))
+            }
+            None => entry.get_next_table()?.translate(virt),
+        }
+    }
+
+    fn unmap(&mut self, mut virt: VirtualAddress, mut size: usize) {
+        let block_size = L::BLOCK_SIZE as usize;
+        while size > 0 {
+            let idx = ((virt.0 as u64 >> L::VIRT_SHIFT_AMT) & 0x1FF) as usize;
+            let chunk = core::cmp::min(size, block_size - (virt.0 & (block_size - 1)));
             let entry = &mut self.entries[idx];
-            let new_virt = VirtualAddress(virt.0 + i * L::BLOCK_SIZE as usize);
-            let new_size = size - i * L::BLOCK_SIZE as usize;
-            if let Some(next_table) = entry.get_next_table_mut() {
-                next_table.unmap(new_virt, new_size);
+            let now_empty = match entry.get_next_table_mut() {
+                Some(next_table) => {
+                    next_table.unmap(virt, chunk);
+                    next_table.is_empty()
+                }
+                None => false,
+            };
+            if now_empty {
+                let table_phys = entry.table_address().unwrap();
+                // tables that weren't allocated in map_to, like the ones the kernel starts with,
+                // are never freed
+                let owned = crate::memory::frame::get(table_phys)
+                    .is_some_and(|info| info.owner() == FrameOwner::PageTable);
+                if owned {
+                    *entry = IntermediateTableEntry::new_invalid();
+                    // this gets rid of any cached walks through the table, too
+                    invalidate_page(virt);
+                    crate::memory::frame::put(table_phys);
+                }
             }
+            virt += chunk;
+            size -= chunk;
         }
     }
 
@@ -291,6 +386,40 @@ impl<L: IntermediateLevel> Table for IntermediateTable<L> {
             this.assume_init_mut()
         }
     }
+
+    fn is_empty(&self) -> bool {
+        self.entries.iter().all(|entry| !entry.is_valid())
+    }
+
+    fn release(&mut self) {
+        for (idx, entry) in self.entries.iter_mut().enumerate() {
+            // the last entry of a user table is the recursive mapping, which points back here
+            if L::IS_TOP_LEVEL && idx == 511 {
+                continue;
+            }
+            if let Some(block) = entry.block_address() {
+                release_frames(block, L::BLOCK_SIZE as usize);
+            } else if let Some(table_phys) = entry.table_address() {
+                entry.get_next_table_mut().unwrap().release();
+                crate::memory::frame::put(table_phys);
+            }
+            *entry = IntermediateTableEntry::new_invalid();
+        }
+        if L::IS_TOP_LEVEL {
+            asid::invalidate(asid::active());
+        }
+    }
+}
+
+/// Drop a reference to each frame in the range that was allocated for user memory.
+fn release_frames(phys: PhysicalAddress, size: usize) {
+    for offset in (0..size).step_by(4096) {
+        let frame = phys + offset;
+        match crate::memory::frame::get(frame) {
+            Some(info) if info.owner() == FrameOwner::UserAnon => crate::memory::frame::put(frame),
+            _ => {}
+        }
+    }
 }
 
 #[derive(Copy, Clone)]
@@ -329,16 +458,19 @@ impl<L: IntermediateLevel> IntermediateTableEntry<L> {
 
     fn new(phys: PhysicalAddress) -> Self {
         let phys = phys.0 as u64 & 0x0000_FFFF_FFFF_F000;
-        let value = phys | 0b11 | (1 << 2) | (1 << 10) | (1 << 6);
+        // the attribute bits are ignored in a table descriptor, but they matter when a table is
+        // reached through a recursive mapping, in which case it should only be visible to the
+        // kernel, and not global, since every address space has its own tables there
+        let value = phys | 0b11 | leaf_attributes(MapFlags::KERNEL_DATA - MapFlags::GLOBAL);
         Self {
             value,
             _marker: PhantomData,
         }
     }
 
-    fn new_block(phys: PhysicalAddress) -> Self {
+    fn new_block(phys: PhysicalAddress, flags: MapFlags) -> Self {
         let phys = phys.0 as u64 & L::BLOCK_ADDRESS_MASK;
-        let value = phys | 0b01 | (1 << 10);
+        let value = phys | 0b01 | leaf_attributes(flags);
         Self {
             value,
             _marker: PhantomData,
@@ -522,7 +654,13 @@ pub trait PageOrBlockDesc {
         set_bit!(self.value_mut(), 10, value);
     }
 
-    //TODO: shareable bits
+    fn get_shareability(&self) -> u64 {
+        (self.value() >> 8) & 0b11
+    }
+
+    fn set_shareability(&mut self, value: u64) {
+        *self.value_mut() = (*self.value() & !(0b11 << 8)) | ((value & 0b11) << 8);
+    }
 
     fn get_read_only(&self) -> bool {
         self.value() & (1 << 7) != 0
@@ -548,7 +686,31 @@ pub trait PageOrBlockDesc {
         set_bit!(self.value_mut(), 5, value);
     }
 
-    //TODO: attrindex
+    fn get_attr_index(&self) -> u64 {
+        (self.value() >> 2) & 0b111
+    }
+
+    fn set_attr_index(&mut self, value: u64) {
+        *self.value_mut() = (*self.value() & !(0b111 << 2)) | ((value & 0b111) << 2);
+    }
+
+    fn get_flags(&self) -> MapFlags {
+        let mut flags = MapFlags::READ;
+        flags.set(MapFlags::WRITE, !self.get_read_only());
+        flags.set(MapFlags::USER, self.get_el0_accessible());
+        flags.set(MapFlags::GLOBAL, !self.get_not_global());
+        flags.set(MapFlags::DEVICE, self.get_attr_index() == ATTR_INDEX_DEVICE);
+        let exec = match flags.contains(MapFlags::USER) {
+            true => !self.get_xn(),
+            false => !self.get_pxn(),
+        };
+        flags.set(MapFlags::EXEC, exec);
+        flags
+    }
+
+    fn set_flags(&mut self, flags: MapFlags) {
+        *self.value_mut() = (*self.value() & !LEAF_ATTRIBUTE_MASK) | leaf_attributes(flags);
+    }
 }
 
 impl PageOrBlockDesc for IntermediateTableEntry<Level1> {
@@ -621,25 +783,83 @@ impl Table for Level3Table {
         virt: VirtualAddress,
         phys: PhysicalAddress,
         size: usize,
+        flags: MapFlags,
     ) -> Result<(), ()> {
+        check_flags(flags)?;
         let start_idx = virt.0 >> 12 & 0x1FF;
-        let end_idx = (virt.0 + size - 1) >> 12 & 0x1FF;
-        for i in start_idx..=end_idx {
-            let entry = &mut self.entries[i];
+        let pages = (size + 4095) / 4096;
+        assert!(start_idx + pages <= 512, "range spans more than one level 3 table");
+        let entries = &self.entries[start_idx..start_idx + pages];
+        if entries.iter().any(|entry| entry.is_valid()) {
+            // changing what a valid entry points at needs the TLB invalidating first; see remap
+            return Err(());
+        }
+        for i in 0..pages {
             let new_phys = PhysicalAddress(phys.0 + i * 4096);
-            *entry = Level3TableEntry::new(new_phys);
+            self.entries[start_idx + i] = Level3TableEntry::new(new_phys, flags);
+        }
+        Ok(())
+    }
+
+    fn protect(&mut self, virt: VirtualAddress, size: usize, flags: MapFlags) {
+        let start_idx = virt.0 >> 12 & 0x1FF;
+        let pages = core::cmp::min((size + 4095) / 4096, 512 - start_idx);
+        for entry in &mut self.entries[start_idx..start_idx + pages] {
+            if entry.is_valid() {
+                entry.set_flags(flags);
+            }
         }
+    }
+
+    fn remap(
+        &mut self,
+        virt: VirtualAddress,
+        phys: PhysicalAddress,
+        flags: MapFlags,
+    ) -> Result<(), ()> {
+        check_flags(flags)?;
+        let entry = &mut self.entries[virt.0 >> 12 & 0x1FF];
+        if !entry.is_valid() {
+            return Err(());
+        }
+        let old = PhysicalAddress(entry.address() as usize);
+        // the old translation has to be gone from every TLB before there's a new one, or a CPU
+        // could end up with both
+        *entry = Level3TableEntry::new_invalid();
+        invalidate_page(virt);
+        *entry = Level3TableEntry::new(phys, flags);
+        unsafe { asm!("dsb ishst", "isb", options(nostack, preserves_flags)) };
+        release_frames(old, 4096);
         Ok(())
     }
 
+    fn translate(&self, virt: VirtualAddress) -> Option<(PhysicalAddress, MapFlags)> {
+        let entry = &self.entries[virt.0 >> 12 & 0x1FF];
+        match entry.is_valid() {
+            true => {
+                let phys = PhysicalAddress(entry.address() as usize + (virt.0 & 0xFFF));
+                Some((phys, entry.get_flags()))
+            }
+            false => None,
+        }
+    }
+
     fn unmap(&mut self, virt: VirtualAddress, size: usize) {
         let starting_idx = virt.0 >> 12 & 0x1FF;
         let mut entries_to_remove = (size + 4095) / 4096;
         if entries_to_remove + starting_idx > 512 {
             entries_to_remove = 512 - starting_idx;
         }
-        for i in starting_idx..starting_idx + entries_to_remove {
-            self.entries[i] = Level3TableEntry::new_invalid();
+        let first_page = virt.0 / 4096 * 4096;
+        let entries = &mut self.entries[starting_idx..starting_idx + entries_to_remove];
+        for (i, entry) in entries.iter_mut().enumerate() {
+            if entry.is_valid() {
+                let phys = PhysicalAddress(entry.address() as usize);
+                *entry = Level3TableEntry::new_invalid();
+                // make sure nothing can reach the frame any more before it's freed
+                invalidate_page(VirtualAddress(first_page + i * 4096));
+                release_frames(phys, 4096);
+            }
         }
     }
 
@@ -653,6 +873,17 @@ impl Table for Level3Table {
             this.assume_init_mut()
         }
     }
+
+    fn is_empty(&self) -> bool {
+        self.entries.iter().all(|entry| !entry.is_valid())
+    }
+
+    fn release(&mut self) {
+        for entry in self.entries.iter_mut().filter(|e| e.is_valid()) {
+            release_frames(PhysicalAddress(entry.address() as usize), 4096);
+            *entry = Level3TableEntry::new_invalid();
+        }
+    }
 }
 
 #[derive(Copy, Clone)]
@@ -668,9 +899,9 @@ impl Debug for Level3TableEntry {
 }
 
 impl Level3TableEntry {
-    const fn new(phys: PhysicalAddress) -> Self {
+    fn new(phys: PhysicalAddress, flags: MapFlags) -> Self {
         let phys = phys.0 as u64 & 0x0000_FFFF_FFFF_F000;
-        let value = phys | 0b11 | (1 << 2) | (1 << 10) | (1 << 6);
+        let value = phys | 0b11 | leaf_attributes(flags);
         Self { value }
     }
 
@@ -0,0 +1,134 @@
+// The kernel command line, e.g. `qemu -append "loglevel=info init=bin/init -- hello world"`
+//
+// Recognised options:
+// - `loglevel=<error|warn|info|debug|trace>`: the most verbose level to log by default
+// - `tracing=<target=level>,...`: override the level for targets starting with `target`, e.g.
+//   `tracing=kernel::vm=debug,kernel::syscall=trace`
+// - `init=<path>`: where init is inside the initrd
+// - `mem=<size>[K|M|G]`: only use the first `size` bytes of RAM
+// - `test`: power off instead of hanging when the kernel panics
+// - `--`: everything after this is passed to init as arguments
+//
+// Anything else is ignored.
+
+use tracing::{Level, Metadata};
+
+static CMDLINE: spin::Once<CommandLine> = spin::Once::new();
+
+const DEFAULT: CommandLine = CommandLine {
+    log_level: Level::TRACE,
+    tracing: "",
+    init: "init",
+    init_args: "",
+    mem_limit: None,
+    test_mode: false,
+};
+
+pub struct CommandLine {
+    pub log_level: Level,
+    /// Comma separated `target=level` directives
+    tracing: &'static str,
+    pub init: &'static str,
+    init_args: &'static str,
+    pub mem_limit: Option<usize>,
+    pub test_mode: bool,
+}
+
+/// Parse the command line. Only the first call does anything. This doesn't allocate, so it can
+/// be called before the kernel heap is set up.
+pub fn init(bootargs: &'static str) {
+    CMDLINE.call_once(|| CommandLine::parse(bootargs));
+}
+
+/// Get the parsed command line, or the defaults if it hasn't been parsed.
+pub fn get() -> &'static CommandLine {
+    CMDLINE.r#try().unwrap_or(&DEFAULT)
+}
+
+impl CommandLine {
+    fn parse(bootargs: &'static str) -> Self {
+        let mut cmdline = DEFAULT;
+        let mut rest = bootargs.trim_end_matches('\0');
+        loop {
+            rest = rest.trim_start();
+            if rest.is_empty() {
+                break;
+            }
+            let (token, after) = rest
+                .split_once(char::is_whitespace)
+                .unwrap_or((rest, ""));
+            rest = after;
+
+            let (key, value) = match token.split_once('=') {
+                Some((key, value)) => (key, Some(value)),
+                None => (token, None),
+            };
+            match (key, value) {
+                ("--", None) => {
+                    cmdline.init_args = rest.trim();
+                    break;
+                }
+                ("loglevel", Some(value)) => {
+                    if let Some(level) = parse_level(value) {
+                        cmdline.log_level = level;
+                    }
+                }
+                ("tracing", Some(value)) => cmdline.tracing = value,
+                ("init", Some(value)) => cmdline.init = value,
+                ("mem", Some(value)) => cmdline.mem_limit = parse_size(value),
+                ("test", None) => cmdline.test_mode = true,
+                _ => {}
+            }
+        }
+        cmdline
+    }
+
+    /// The arguments to pass to init after its own name.
+    pub fn init_args(&self) -> impl Iterator<Item = &'static str> {
+        self.init_args.split_ascii_whitespace()
+    }
+
+    /// Whether a span or event should be logged, according to `tracing` and `loglevel`.
+    pub fn log_enabled(&self, metadata: &Metadata<'_>) -> bool {
+        // the most specific matching directive wins
+        let mut best: Option<(usize, Level)> = None;
+        for directive in self.tracing.split(',') {
+            let (target, level) = match directive.split_once('=') {
+                Some((target, level)) => match parse_level(level) {
+                    Some(level) => (target, level),
+                    None => continue,
+                },
+                None => continue,
+            };
+            if metadata.target().starts_with(target)
+                && best.map_or(true, |(len, _)| target.len() >= len)
+            {
+                best = Some((target.len(), level));
+            }
+        }
+        let max = best.map_or(self.log_level, |(_, level)| level);
+        // more verbose levels compare greater
+        *metadata.level() <= max
+    }
+}
+
+fn parse_level(s: &str) -> Option<Level> {
+    match s {
+        "error" => Some(Level::ERROR),
+        "warn" => Some(Level::WARN),
+        "info" => Some(Level::INFO),
+        "debug" => Some(Level::DEBUG),
+        "trace" => Some(Level::TRACE),
+        _ => None,
+    }
+}
+
+fn parse_size(s: &str) -> Option<usize> {
+    let (digits, multiplier) = match s.as_bytes().last()? {
+        b'K' | b'k' => (&s[..s.len() - 1], 1 << 10),
+        b'M' | b'm' => (&s[..s.len() - 1], 1 << 20),
+        b'G' | b'g' => (&s[..s.len() - 1], 1 << 30),
+        _ => (s, 1),
+    };
+    digits.parse::<usize>().ok()?.checked_mul(multiplier)
+}
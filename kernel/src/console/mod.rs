@@ -1,8 +1,24 @@
 use core::fmt::{Result, Write};
 
-pub mod virt;
+use alloc::vec::Vec;
+use ring_buffer::RingBuffer;
 
-pub static mut WRITER: Writer = Writer(virt::virt_putchar);
+use crate::context::{sched, ActiveContextHandle};
+
+/// Where output goes. Nothing comes out until the arch code finds a console device. It's locked
+/// so that CPUs printing at the same time don't get mixed up.
+pub static WRITER: spin::Mutex<Writer> = spin::Mutex::new(Writer(discard));
+
+/// Input that has arrived and not been read yet, and the contexts waiting for more
+static INPUT: spin::Mutex<Input> = spin::Mutex::new(Input {
+    buffer: RingBuffer::new(),
+    readers: Vec::new(),
+});
+
+struct Input {
+    buffer: RingBuffer<u8, 1024>,
+    readers: Vec<usize>,
+}
 
 pub struct Writer(pub fn(u8));
 
@@ -15,8 +31,55 @@ impl Write for Writer {
     }
 }
 
-pub fn get_writer() -> &'static mut Writer {
-    unsafe { &mut WRITER }
+/// Lock the console for output. Everything written before the guard is dropped comes out in one
+/// piece.
+pub fn get_writer() -> spin::MutexGuard<'static, Writer> {
+    WRITER.lock()
+}
+
+fn discard(_: u8) {}
+
+/// Called by the console device with the bytes it has just received. Whatever doesn't fit in the
+/// buffer is dropped.
+pub fn receive(bytes: impl Iterator<Item = u8>) {
+    let readers = {
+        let mut input = INPUT.lock();
+        for byte in bytes {
+            let _ = input.buffer.try_insert(byte);
+        }
+        core::mem::take(&mut input.readers)
+    };
+    for id in readers {
+        sched::wake(id);
+    }
+}
+
+/// Read whatever input there is into `buf`, waiting for some if there isn't any yet. Returns how
+/// many bytes were read, which is only 0 if `buf` is empty.
+pub fn read(current: &mut ActiveContextHandle, buf: &mut [u8]) -> usize {
+    if buf.is_empty() {
+        return 0;
+    }
+    loop {
+        {
+            let mut input = INPUT.lock();
+            let mut len = 0;
+            while len < buf.len() {
+                match input.buffer.try_get() {
+                    Some(byte) => buf[len] = byte,
+                    None => break,
+                }
+                len += 1;
+            }
+            if len > 0 {
+                return len;
+            }
+            // receive can't get in before we're on the list, since it needs the lock
+            input.readers.push(current.context().id);
+            sched::prepare_to_block(current);
+        }
+        sched::block(current);
+    }
 }
 
 // Thanks, Redox!
@@ -2,27 +2,52 @@ use core::{
     cell::UnsafeCell,
     mem::ManuallyDrop,
     pin::Pin,
-    sync::atomic::{AtomicBool, Ordering},
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
 };
 
 use alloc::{boxed::Box, collections::BTreeMap, vec::Vec};
-use ring_buffer::RingBuffer;
 
 use crate::{
-    arch::context::{ActiveContext, SuspendedContext},
-    vm::{Mapping, VirtualAddress},
+    arch::{
+        context::{ActiveContext, KernelState, SuspendedContext},
+        stack::KernelStack,
+    },
+    exec,
+    vm::{address_space, AddressSpace, TopLevelTable, VirtualAddress, Vma},
 };
 
-// TODO: make it not static mut
-pub static mut CONTEXTS: BTreeMap<usize, Pin<Box<Context>>> = BTreeMap::new();
-pub static SCHED_QUEUE: RingBuffer<usize, 512> = RingBuffer::new();
+pub mod sched;
+
+/// Every context there is. A context is only removed (and freed) by the reaper, once it has exited
+/// and no CPU is using it any more, so references to contexts can outlive the lock until then.
+pub static CONTEXTS: spin::Mutex<BTreeMap<usize, Pin<Box<Context>>>> =
+    spin::Mutex::new(BTreeMap::new());
+/// How contexts that have exited went, kept until their parents ask. When both this and
+/// [`CONTEXTS`] are locked, this one is locked first.
+static EXIT_STATUSES: spin::Mutex<BTreeMap<usize, ExitStatus>> = spin::Mutex::new(BTreeMap::new());
+/// The id of the next context to be created. 0 is init, which is made by hand.
+static NEXT_ID: AtomicUsize = AtomicUsize::new(1);
+/// The id of the kernel thread that frees contexts once they've exited
+static REAPER: spin::Once<usize> = spin::Once::new();
 
 pub struct Context {
     pub id: usize,
-    /// Be very very careful with this one!
+    pub kind: Kind,
+    /// Be very very careful with this one! It's set while a CPU is using the context, from when
+    /// the CPU claims it to be switched to until the CPU is done switching away from it.
     active: AtomicBool,
+    state: spin::Mutex<RunState>,
+    /// The context that spawned this one, if any
+    pub parent: Option<usize>,
+    /// The context blocked waiting for this one to exit, if any
+    waiter: spin::Mutex<Option<usize>>,
     /// Data that can only be mutably borrowed by a CPU if the context is active there
     thread_local: UnsafeCell<ThreadLocal>,
+    /// Where the context's registers go when it traps into the kernel
+    kernel_stack: KernelStack,
+    /// Where the context was in the kernel when it was switched away from, if it was in the middle
+    /// of something. Otherwise, it goes straight back to userspace when it's next switched to.
+    kernel_state: UnsafeCell<Option<KernelState>>,
     /// Arch-specific state if the context is suspended, or uninit if it's active
     arch: UnsafeCell<ArchContext>,
 }
@@ -32,21 +57,98 @@ union ArchContext {
     active: ManuallyDrop<ActiveContext>,
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    /// A user process, which spends most of its time in userspace
+    Process,
+    /// Kernel code with a context of its own, so that it can block and be scheduled like a
+    /// process. It has no user memory and never leaves the kernel.
+    KernelThread,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct ExitStatus {
+    pub parent: usize,
+    pub code: i32,
+}
+
+/// Whether the scheduler should consider running a context. A context that is currently running
+/// is [`RunState::Runnable`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RunState {
+    Runnable,
+    /// Waiting for something to happen; it goes back on the run queue when it's woken
+    Blocked,
+    /// Finished with the given exit code, and never going to run again
+    Exited(i32),
+}
+
 #[repr(transparent)]
 pub struct ActiveContextHandle(pub *const Context);
 
 impl Context {
-    pub fn new(id: usize) -> Pin<Box<Self>> {
+    pub fn new(id: usize, parent: Option<usize>) -> Pin<Box<Self>> {
+        Self::with_kind(id, parent, Kind::Process)
+    }
+
+    fn with_kind(id: usize, parent: Option<usize>, kind: Kind) -> Pin<Box<Self>> {
         Box::pin(Context {
             id,
+            kind,
             active: AtomicBool::new(false),
+            state: spin::Mutex::new(RunState::Runnable),
+            parent,
+            waiter: spin::Mutex::new(None),
             thread_local: UnsafeCell::new(ThreadLocal::new()),
+            kernel_stack: KernelStack::new(),
+            kernel_state: UnsafeCell::new(None),
             arch: UnsafeCell::new(ArchContext {
                 suspended: ManuallyDrop::new(SuspendedContext::new()),
             }),
         })
     }
 
+    pub fn state(&self) -> RunState {
+        *self.state.lock()
+    }
+
+    pub fn set_state(&self, state: RunState) {
+        *self.state.lock() = state;
+    }
+
+    /// Remember that `id` is blocked until this context exits.
+    pub fn set_waiter(&self, id: usize) {
+        *self.waiter.lock() = Some(id);
+    }
+
+    /// Take where the context should carry on from when it's switched to.
+    ///
+    /// # Safety
+    /// The context must have just been switched to on this CPU, and whatever was running before
+    /// must not touch the state until it's been resumed.
+    unsafe fn take_kernel_state(&self) -> KernelState {
+        (*self.kernel_state.get())
+            .take()
+            .unwrap_or_else(|| KernelState::new(self.kernel_stack.top(), return_to_user, 0))
+    }
+
+    /// Mark the context as active so that this CPU can switch to it. Returns false if another CPU
+    /// is using it.
+    fn claim(&self) -> bool {
+        self.active
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+    }
+
+    /// Let other CPUs switch to the context.
+    ///
+    /// # Safety
+    /// The context must have been switched away from, and this CPU must be done with it, down to
+    /// its kernel stack.
+    unsafe fn release(&self) {
+        self.active.store(false, Ordering::Release);
+    }
+
     /// # Safety
     /// Must be called when no other context is active on this CPU. This is either at boot time or
     /// in the implementation of [`ActiveContextHandle::switch_to`].
@@ -89,47 +191,90 @@ impl ActiveContextHandle {
         unsafe { &mut (*(*self.0).arch.get()).active }
     }
 
+    /// Set up a fresh, empty user address space.
+    pub fn init(&mut self) {
+        self.arch().init();
+    }
+
+    /// Get the user address space of the context along with its page tables.
+    pub fn memory(&mut self) -> (&mut AddressSpace, &mut TopLevelTable) {
+        // safety: the context is active on this CPU, so nothing else can be touching its thread
+        // local data
+        let thread_local = unsafe { &mut *(*self.0).thread_local.get() };
+        // safety: [`init`] has been called if the address space has anything in it; otherwise
+        // the caller gets what they deserve
+        let table = unsafe { self.arch().table() };
+        (&mut thread_local.address_space, table)
+    }
+
     pub fn set_entry_point(&mut self, virt: VirtualAddress) {
         self.arch().set_entry_point(virt)
     }
 
     pub unsafe fn jump_to_userspace(&mut self) -> ! {
-        self.arch().jump_to_userspace()
+        let stack_top = self.context().kernel_stack.top();
+        self.arch().jump_to_userspace(stack_top)
+    }
+
+    /// Switch to `other` to do something that can only be done while it's active, such as
+    /// setting up its address space, then switch back to self.
+    pub fn run_as<R>(
+        &mut self,
+        other: &Context,
+        f: impl FnOnce(&mut ActiveContextHandle) -> R,
+    ) -> R {
+        // safety: self is forgotten by switch_to, and replaced with an equivalent handle once
+        // we're back
+        let this = unsafe { core::ptr::read(self) };
+        let mut other_handle = this.switch_to(other);
+        let ret = f(&mut other_handle);
+        let back = other_handle.switch_to(self.context());
+        core::mem::forget(back);
+        ret
     }
 
     pub fn switch_to(self, other: &Context) -> Self {
         if core::ptr::eq(self.context(), other) {
             return self;
         }
+        if !other.claim() {
+            panic!("tried to switch to an active context!");
+        }
+        let this = self.0;
         unsafe {
-            let self_active = ManuallyDrop::into_inner(self.context().arch.get().read().active);
-            let activate =
-                other
-                    .active
-                    .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed);
-            if activate.is_err() {
-                panic!("tried to switch to an active context!");
-            }
-            let other_suspended = ManuallyDrop::into_inner(other.arch.get().read().suspended);
-            let (self_suspended, _) = self_active.suspend();
-            self.context().arch.get().write(ArchContext {
-                suspended: ManuallyDrop::new(self_suspended),
-            });
-            // by self existing, active is false, so we don't need compare-exchange
-            self.context().active.store(false, Ordering::Release);
-            core::mem::forget(self);
-            let other_ptr = other as *const _;
-            other_suspended.enter(other_ptr);
-            ActiveContextHandle(other_ptr)
+            let other = self.switch_to_claimed(other);
+            // nothing of self's is in use any more, since we stay on the same kernel stack
+            (*this).release();
+            other
         }
     }
+
+    /// Switch to `other`, which this CPU has already claimed, leaving self marked as active.
+    /// Whoever calls this has to release self once they're off its kernel stack.
+    ///
+    /// # Safety
+    /// `other` must have been claimed by this CPU.
+    unsafe fn switch_to_claimed(self, other: &Context) -> Self {
+        let self_active = ManuallyDrop::into_inner(self.context().arch.get().read().active);
+        let other_suspended = ManuallyDrop::into_inner(other.arch.get().read().suspended);
+        let self_suspended = self_active.suspend();
+        self.context().arch.get().write(ArchContext {
+            suspended: ManuallyDrop::new(self_suspended),
+        });
+        core::mem::forget(self);
+        let other_ptr = other as *const _;
+        other_suspended.enter(other_ptr);
+        ActiveContextHandle(other_ptr)
+    }
 }
 
 impl Drop for Context {
     fn drop(&mut self) {
         if self.active.load(Ordering::Relaxed) {
             tracing::error!("Context dropped while active! Expect ghosts!");
+            return;
         }
+        unsafe { ManuallyDrop::take(&mut (*self.arch.get()).suspended) }.free();
     }
 }
 
@@ -140,20 +285,243 @@ impl Drop for ActiveContextHandle {
 }
 
 pub struct ThreadLocal {
-    text: Vec<Mapping>,
+    address_space: AddressSpace,
 }
 
 impl ThreadLocal {
     fn new() -> Self {
-        ThreadLocal { text: Vec::new() }
+        ThreadLocal {
+            address_space: AddressSpace::new(),
+        }
     }
 }
 
-pub fn exit() -> ! {
-    // if CURRENT_CONTEXT.load(Ordering::Relaxed) == 0 {
-    // safety: only running on qemu means system is always psci :)
-    unsafe { crate::arch::platform::shutdown() };
-    // } else {
-    // todo!()
-    // }
+/// Add a new context to [`CONTEXTS`]. It stays there until it exits, so the returned reference is
+/// good until then.
+pub fn insert(context: Pin<Box<Context>>) -> &'static Context {
+    let id = context.id;
+    let mut contexts = CONTEXTS.lock();
+    let context = contexts.entry(id).or_insert(context);
+    // safety: contexts are boxed, so they don't move when the map changes
+    unsafe { &*(&**context as *const Context) }
+}
+
+/// Create a new context running `file`, as a child of `parent`, and queue it to run. Returns the
+/// id of the new context.
+pub fn spawn(
+    parent: &mut ActiveContextHandle,
+    file: &[u8],
+    argv: &[&str],
+) -> Result<usize, exec::Error> {
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let context = Context::new(id, Some(parent.context().id));
+    parent.run_as(&context, |child| {
+        child.init();
+        let res = exec::exec(child, file, argv, &[], exec::DEFAULT_STACK_SIZE);
+        if res.is_err() {
+            // get rid of whatever was loaded before it went wrong
+            let (space, table) = child.memory();
+            space.clear(table);
+        }
+        res
+    })?;
+    insert(context);
+    sched::enqueue(id);
+    Ok(id)
+}
+
+/// Create a copy of `parent` that shares its memory copy-on-write, and queue it to run. The child
+/// carries on from the same place as the parent, after `setup` has had a chance to make it
+/// different. Returns the id of the child.
+pub fn fork(
+    parent: &mut ActiveContextHandle,
+    setup: impl FnOnce(&mut ActiveContextHandle),
+) -> Result<usize, address_space::Error> {
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let context = Context::new(id, Some(parent.context().id));
+    let state = parent.arch().clone();
+    let sp = parent.arch().stack_pointer();
+    let tpidr = parent.arch().thread_pointer();
+    let parent_table = crate::arch::vm::get_current_user_table();
+    let areas: Vec<Vma> = parent.memory().0.areas().cloned().collect();
+    parent.run_as(&context, |child| {
+        child.init();
+        *child.arch() = state;
+        child.arch().set_stack_pointer(sp);
+        child.arch().set_thread_pointer(tpidr);
+        setup(child);
+        let (space, table) = child.memory();
+        // safety: the parent is suspended, so nothing else is touching its table
+        let parent_table = unsafe { crate::arch::vm::table_at(parent_table) };
+        let res = space.copy_on_write_from(table, &areas, parent_table);
+        if res.is_err() {
+            space.clear(table);
+        }
+        res
+    })?;
+    // the child can't run until it's queued, so the parent's pages only have to be read-only by
+    // then
+    let (space, table) = parent.memory();
+    space.write_protect_private(table);
+    insert(context);
+    sched::enqueue(id);
+    Ok(id)
+}
+
+/// Stop running the current context for good: free its memory, let whoever is waiting for it
+/// know, and run something else. The context itself is freed once it's no longer running.
+///
+/// When init or the last remaining context exits, there's nothing left to do, so the machine is
+/// powered off.
+pub fn exit(mut current: ActiveContextHandle, code: i32) -> ! {
+    let id = current.context().id;
+    tracing::debug!(id, code, "context exited");
+    let (space, table) = current.memory();
+    space.clear(table);
+
+    let context = current.context();
+    let mut statuses = EXIT_STATUSES.lock();
+    let parent_alive;
+    {
+        let contexts = CONTEXTS.lock();
+        // checked under the lock, so that of two processes exiting at once, one sees the other
+        let others = contexts.values().any(|context| {
+            context.id != id
+                && context.kind == Kind::Process
+                && !matches!(context.state(), RunState::Exited(_))
+        });
+        if id == 0 || !others {
+            // if shutting down doesn't work, this CPU stops here, and the other CPUs had better
+            // not be left waiting for the locks
+            drop(contexts);
+            drop(statuses);
+            tracing::info!(id, code, "last process exited; shutting down");
+            crate::arch::platform::exit(code);
+        }
+        context.set_state(RunState::Exited(code));
+        parent_alive = context
+            .parent
+            .filter(|p| {
+                contexts
+                    .get(p)
+                    .is_some_and(|p| !matches!(p.state(), RunState::Exited(_)))
+            })
+            .is_some();
+    }
+    // our own children's exit statuses are no use to anyone now
+    statuses.retain(|_, status| status.parent != id);
+    if let (Some(parent), true) = (context.parent, parent_alive) {
+        statuses.insert(id, ExitStatus { parent, code });
+    }
+    let waiter = context.waiter.lock().take();
+    drop(statuses);
+    if let Some(waiter) = waiter {
+        sched::wake(waiter);
+    }
+    // the reaper is woken once we've been switched away from
+    sched::schedule(current)
+}
+
+/// Wait for `id`, a child of the current context, to exit, and take its exit status. Returns
+/// `None` if there's no such child.
+pub fn wait(current: &mut ActiveContextHandle, id: usize) -> Option<ExitStatus> {
+    let parent = current.context().id;
+    loop {
+        let mut statuses = EXIT_STATUSES.lock();
+        match statuses.get(&id) {
+            Some(status) if status.parent == parent => return statuses.remove(&id),
+            _ => {}
+        }
+        {
+            let contexts = CONTEXTS.lock();
+            match contexts.get(&id) {
+                // its status is already gone, so there's nothing that would ever wake us
+                Some(child) if matches!(child.state(), RunState::Exited(_)) => return None,
+                Some(child) if child.parent == Some(parent) => {
+                    // the child can't exit in between, since it needs EXIT_STATUSES to do that,
+                    // so it'll see that we're waiting
+                    child.set_waiter(parent);
+                    sched::prepare_to_block(current);
+                }
+                _ => return None,
+            }
+        }
+        drop(statuses);
+        // look again once the child has exited, when its status will be there
+        sched::block(current);
+    }
+}
+
+/// Create a kernel thread that runs `entry`, without queueing it to run. Kernel threads are never
+/// preempted, since interrupts are masked in the kernel, so they have to [`sched::block`] or
+/// [`sched::yield_now`] to let anything else run.
+fn create_kernel_thread(entry: fn(ActiveContextHandle) -> !) -> &'static Context {
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let context = Context::with_kind(id, None, Kind::KernelThread);
+    let state = KernelState::new(
+        context.kernel_stack.top(),
+        kernel_thread_start,
+        entry as usize,
+    );
+    // safety: the context isn't anywhere that anything else could see it yet
+    unsafe { *context.kernel_state.get() = Some(state) };
+    insert(context)
+}
+
+/// Create a kernel thread that runs `entry` (see [`create_kernel_thread`]), and queue it to run.
+/// Returns the id of the thread.
+pub fn spawn_kernel_thread(entry: fn(ActiveContextHandle) -> !) -> usize {
+    let id = create_kernel_thread(entry).id;
+    sched::enqueue(id);
+    id
+}
+
+/// Start the kernel thread that frees contexts that have exited.
+pub fn start_reaper() {
+    REAPER.call_once(|| spawn_kernel_thread(reaper));
+}
+
+/// Wake the reaper up, because a context has exited and been switched away from.
+fn wake_reaper() {
+    if let Some(&reaper) = REAPER.r#try() {
+        sched::wake(reaper);
+    }
+}
+
+/// Free contexts that have exited whenever one does, once no CPU is using them. This happens on
+/// the reaper's own stack, so the kernel stacks being freed can't be in use.
+fn reaper(mut current: ActiveContextHandle) -> ! {
+    loop {
+        sched::prepare_to_block(&current);
+        let dead: Vec<Pin<Box<Context>>> = {
+            let mut contexts = CONTEXTS.lock();
+            let ids: Vec<usize> = contexts
+                .values()
+                .filter(|context| {
+                    matches!(context.state(), RunState::Exited(_))
+                        && !context.active.load(Ordering::Acquire)
+                })
+                .map(|context| context.id)
+                .collect();
+            ids.iter().filter_map(|id| contexts.remove(id)).collect()
+        };
+        // freeing them takes a while, so it's done without holding up everyone else
+        drop(dead);
+        sched::block(&mut current);
+    }
+}
+
+extern "C" fn kernel_thread_start(entry: usize) -> ! {
+    sched::finish_switch();
+    // safety: it was a fn(ActiveContextHandle) -> ! when it went into the kernel state
+    let entry: fn(ActiveContextHandle) -> ! = unsafe { core::mem::transmute(entry) };
+    entry(ActiveContextHandle(crate::arch::context::current()))
+}
+
+/// Where a process carries on from when it's switched to and it wasn't in the middle of anything
+/// in the kernel.
+extern "C" fn return_to_user(_: usize) -> ! {
+    sched::finish_switch();
+    let mut current = ActiveContextHandle(crate::arch::context::current());
+    unsafe { current.jump_to_userspace() }
 }
@@ -0,0 +1,304 @@
+// Round-robin scheduling of contexts, with a run queue per CPU
+//
+// Every runnable context that isn't running sits in one of the run queues. A context runs until it
+// yields, blocks or exits, or until its time slice runs out and the timer interrupt preempts it
+// (which only happens in userspace). Either way it goes to the back of a queue (if it can still
+// run) and the one at the front runs next. A CPU with nothing in its own queue takes work from the
+// others, and runs its idle thread if there isn't any.
+//
+// Processes that give up the CPU from a syscall or an interrupt usually have nothing on their
+// kernel stack worth keeping, and just go back to userspace when they next run. Anything that
+// blocks in the middle of kernel code, like a kernel thread, keeps its kernel stack and carries
+// on where it left off.
+//
+// A context stays active until the CPU switching away from it is off its kernel stack, and only
+// then goes back on a queue (see `finish_switch`), so no CPU can pick up a context that another is
+// still using. That also means a context that's woken while it's on its way out is left for
+// `finish_switch` to queue.
+
+use core::{
+    ptr::null_mut,
+    sync::atomic::{fence, AtomicBool, AtomicPtr, Ordering},
+};
+
+use alloc::collections::VecDeque;
+
+use crate::{
+    arch::{
+        context::KernelState,
+        percpu::{self, MAX_CPUS},
+        timer,
+    },
+    vm::VirtualAddress,
+};
+
+use super::{ActiveContextHandle, Context, RunState, CONTEXTS};
+
+/// The scheduler's side of a CPU
+struct Cpu {
+    /// Runnable contexts waiting for a go
+    queue: spin::Mutex<VecDeque<usize>>,
+    /// Set by interrupt handlers to switch contexts on the way out of the interrupt
+    need_reschedule: AtomicBool,
+    /// What runs when nothing else can. It never goes on a queue.
+    idle: AtomicPtr<Context>,
+    /// Whether the idle thread is what's running
+    idling: AtomicBool,
+    /// The context the CPU has just switched away from, which it's still using until it gets to
+    /// [`finish_switch`]
+    previous: AtomicPtr<Context>,
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const CPU: Cpu = Cpu {
+    queue: spin::Mutex::new(VecDeque::new()),
+    need_reschedule: AtomicBool::new(false),
+    idle: AtomicPtr::new(null_mut()),
+    idling: AtomicBool::new(false),
+    previous: AtomicPtr::new(null_mut()),
+};
+static CPUS: [Cpu; MAX_CPUS] = [CPU; MAX_CPUS];
+
+fn this_cpu() -> &'static Cpu {
+    &CPUS[percpu::id()]
+}
+
+/// Ask for the current context to be switched away from on the way out of this interrupt.
+pub fn request_reschedule() {
+    this_cpu().need_reschedule.store(true, Ordering::Relaxed);
+}
+
+/// Returns true, once, if a reschedule has been asked for since the last call.
+pub fn take_reschedule() -> bool {
+    this_cpu().need_reschedule.swap(false, Ordering::Relaxed)
+}
+
+/// Make `id` runnable and put it on a run queue, e.g. when something it was waiting for has
+/// happened.
+pub fn wake(id: usize) {
+    let contexts = CONTEXTS.lock();
+    let context = match contexts.get(&id) {
+        Some(context) => context,
+        None => return,
+    };
+    {
+        let mut state = context.state.lock();
+        if *state != RunState::Blocked {
+            return;
+        }
+        *state = RunState::Runnable;
+    }
+    // pairs with the fence in finish_switch: either we see that the context has been let go of,
+    // or whoever let go of it sees that it's runnable
+    fence(Ordering::SeqCst);
+    if !context.active.load(Ordering::Relaxed) {
+        enqueue(id);
+    }
+}
+
+/// Add a context that has just become runnable to the back of the shortest run queue, counting
+/// whatever each CPU is running, and poke that CPU if it's idling so that it notices.
+pub fn enqueue(id: usize) {
+    let this = percpu::id();
+    let load = |cpu: usize| {
+        let busy = !CPUS[cpu].idling.load(Ordering::Relaxed);
+        (CPUS[cpu].queue.lock().len() + busy as usize, cpu != this)
+    };
+    let target = (0..percpu::online())
+        .min_by_key(|&cpu| load(cpu))
+        .unwrap_or(this);
+    CPUS[target].queue.lock().push_back(id);
+    if target != this && CPUS[target].idling.load(Ordering::Relaxed) {
+        crate::arch::smp::kick(target);
+    }
+}
+
+/// Mark the current context as blocked, ahead of a call to [`block`]. Code waiting for something
+/// should do this before checking whether it's happened, so that a [`wake`] in between isn't
+/// lost.
+pub fn prepare_to_block(current: &ActiveContextHandle) {
+    current.context().set_state(RunState::Blocked);
+}
+
+/// Stop running the current context until someone [`wake`]s it, and return once they have. It
+/// has to have been marked as blocked with [`prepare_to_block`]; if it's been woken since, this
+/// returns straight away. Everything on the kernel stack is kept in the meantime, so this can be
+/// used anywhere in the kernel.
+pub fn block(current: &mut ActiveContextHandle) {
+    if current.context().state() == RunState::Blocked {
+        switch_away(current);
+    }
+}
+
+/// Let everything else on the run queue have a go, then carry on. This is how kernel threads
+/// share the CPU, since nothing preempts them.
+pub fn yield_now(current: &mut ActiveContextHandle) {
+    switch_away(current);
+}
+
+/// Stop running the current context and run the next runnable one instead, throwing away
+/// everything on the kernel stack. If the current context is still runnable it goes to the back
+/// of a queue, so it runs again eventually; otherwise it runs again when someone [`wake`]s it.
+/// Either way, it goes straight back to userspace, so this is only for processes.
+pub fn schedule(mut current: ActiveContextHandle) -> ! {
+    let next = pick_next(&current);
+    if core::ptr::eq(next, current.context()) {
+        timer::start_slice();
+        unsafe { current.jump_to_userspace() }
+    }
+    tracing::trace!(
+        from = current.context().id,
+        to = next.id,
+        "switching context"
+    );
+    let previous = current.0;
+    // safety: pick_next claimed next. whatever carries on as it gets its own handle
+    core::mem::forget(unsafe { current.switch_to_claimed(next) });
+    switched(previous, next);
+    // safety: next has just been switched to, and the stack we're abandoning isn't its
+    unsafe { next.take_kernel_state().resume() }
+}
+
+/// Run other contexts until the current one is runnable and picked to run again, which might be on
+/// another CPU.
+fn switch_away(current: &mut ActiveContextHandle) {
+    let next = pick_next(current);
+    if core::ptr::eq(next, current.context()) {
+        return;
+    }
+    tracing::trace!(
+        from = current.context().id,
+        to = next.id,
+        "switching context"
+    );
+    let state = current.context().kernel_state.get();
+    // safety: switch_to_claimed forgets this, and current is back to being valid by the time we
+    // return. pick_next claimed next
+    let this = unsafe { core::ptr::read(current) };
+    core::mem::forget(unsafe { this.switch_to_claimed(next) });
+    switched(current.0, next);
+    // safety: nothing looks at our kernel state until we've been let go of, which finish_switch
+    // does once it's saved and we're off our stack
+    unsafe {
+        let to = next.take_kernel_state();
+        (*state).insert(KernelState::default()).switch(&to);
+    }
+    finish_switch();
+}
+
+/// Bookkeeping for a switch from `previous` to `next`, which is about to start running.
+fn switched(previous: *const Context, next: &Context) {
+    let cpu = this_cpu();
+    cpu.previous.store(previous as *mut _, Ordering::Relaxed);
+    cpu.idling.store(
+        core::ptr::eq(next, cpu.idle.load(Ordering::Relaxed)),
+        Ordering::Relaxed,
+    );
+    timer::start_slice();
+}
+
+/// Let go of the context this CPU just switched away from, now that it's off its kernel stack,
+/// and queue it if it can still run. Everywhere a context can carry on from after a switch calls
+/// this first.
+pub(super) fn finish_switch() {
+    let cpu = this_cpu();
+    let previous = cpu.previous.swap(null_mut(), Ordering::Relaxed);
+    // safety: previous can't be freed until it's been let go of, below
+    let previous = match unsafe { previous.as_ref() } {
+        Some(previous) => previous,
+        // nothing ran before on this CPU
+        None => return,
+    };
+    let id = previous.id;
+    let exited = matches!(previous.state(), RunState::Exited(_));
+    let idle = core::ptr::eq(previous, cpu.idle.load(Ordering::Relaxed));
+    // safety: we're done with it, and previous mustn't be touched from here on, since another CPU
+    // could pick it up or the reaper could free it
+    unsafe { previous.release() };
+    fence(Ordering::SeqCst);
+
+    if exited {
+        super::wake_reaper();
+    } else if !idle {
+        let contexts = CONTEXTS.lock();
+        let runnable = contexts.get(&id).is_some_and(|context| {
+            context.state() == RunState::Runnable && !context.active.load(Ordering::Relaxed)
+        });
+        drop(contexts);
+        if runnable {
+            enqueue(id);
+        }
+    }
+}
+
+/// Find something else for this CPU to run, and claim it: the first context that can run in this
+/// CPU's queue, or failing that in another CPU's. If there isn't one, it's `current` if that can
+/// still run, or else this CPU's idle thread.
+fn pick_next(current: &ActiveContextHandle) -> &'static Context {
+    let this = percpu::id();
+    for cpu in (this..percpu::online()).chain(0..this) {
+        loop {
+            let id = match CPUS[cpu].queue.lock().pop_front() {
+                Some(id) => id,
+                None => break,
+            };
+            // anything that can't be claimed has exited or blocked since it was queued, or is
+            // still being switched away from, in which case it'll be queued again
+            if let Some(next) = claim(id) {
+                return next;
+            }
+        }
+    }
+    // safety: the current context can't be freed while it's running
+    let current = unsafe { &*current.0 };
+    if current.state() == RunState::Runnable {
+        return current;
+    }
+    let idle = this_cpu().idle.load(Ordering::Relaxed);
+    // safety: idle threads are never freed
+    unsafe { &*idle }
+}
+
+/// Claim `id` to be switched to by this CPU, if it can run.
+fn claim(id: usize) -> Option<&'static Context> {
+    let contexts = CONTEXTS.lock();
+    let context = contexts.get(&id)?;
+    if context.state() != RunState::Runnable || !context.claim() {
+        return None;
+    }
+    // safety: a context is only freed once it's exited, which this one can't do until it's run
+    Some(unsafe { &*(&**context as *const Context) })
+}
+
+/// Make the idle thread for CPU number `cpu`. Returns the top of its stack, which the CPU can use
+/// until it starts running the idle thread, since that starts from the top again.
+pub fn create_idle_thread(cpu: usize) -> VirtualAddress {
+    let idle = super::create_kernel_thread(idle);
+    CPUS[cpu]
+        .idle
+        .store(idle as *const _ as *mut _, Ordering::Relaxed);
+    idle.kernel_stack.top()
+}
+
+/// Start scheduling on a CPU that's just come up, by running its idle thread.
+pub fn start() -> ! {
+    let cpu = this_cpu();
+    // safety: idle threads are never freed
+    let idle = unsafe { &*cpu.idle.load(Ordering::Relaxed) };
+    // safety: nothing has run on this CPU yet
+    core::mem::forget(unsafe { idle.enter() });
+    cpu.idling.store(true, Ordering::Relaxed);
+    // safety: idle has just been switched to, and the stack we're on is about to be abandoned
+    unsafe { idle.take_kernel_state().resume() }
+}
+
+/// What a CPU runs when there's nothing else to do: look for work, and if there isn't any, wait for
+/// an interrupt, e.g. from another CPU that has queued something. Anything queued in between is
+/// still noticed, since the interrupt that comes with it wakes the CPU straight back up.
+fn idle(mut current: ActiveContextHandle) -> ! {
+    loop {
+        yield_now(&mut current);
+        crate::arch::wait_for_interrupt();
+        take_reschedule();
+    }
+}
@@ -1,34 +1,226 @@
+use alloc::vec::Vec;
+use goblin::elf::{
+    header::{EM_AARCH64, ET_DYN, ET_EXEC},
+    program_header::{PF_R, PF_W, PF_X, PT_LOAD, PT_PHDR},
+    reloc::R_AARCH64_RELATIVE,
+};
+
 use crate::{
+    arch::FRAME_SIZE,
     context::ActiveContextHandle,
     fmt::ForceLowerHex,
-    vm::{Table, VirtualAddress},
+    vm::{address_space, Backing, Protection, VirtualAddress},
 };
 
-pub fn load_elf(
-    file: &[u8],
-    context: &mut ActiveContextHandle,
-) -> Result<(), goblin::error::Error> {
+/// Where position independent executables get loaded
+const PIE_LOAD_BIAS: usize = 0x0000_5555_5555_0000;
+
+#[derive(Debug)]
+pub enum Error {
+    Parse(goblin::error::Error),
+    /// The file isn't a 64-bit little endian aarch64 executable
+    UnsupportedFormat,
+    /// The file is an object file, core dump or something else that can't be run
+    UnsupportedType(u16),
+    /// A segment is bigger in the file than in memory, extends past the end of the file, or isn't
+    /// after the segment before it in memory
+    BadSegment,
+    UnsupportedRelocation(u32),
+    /// A relocation points outside of the writable parts of the loaded segments
+    BadRelocation,
+    Map(address_space::Error),
+}
+
+impl From<goblin::error::Error> for Error {
+    fn from(e: goblin::error::Error) -> Self {
+        Error::Parse(e)
+    }
+}
+
+impl From<address_space::Error> for Error {
+    fn from(e: address_space::Error) -> Self {
+        Error::Map(e)
+    }
+}
+
+/// Information about a loaded executable that the program might want to know, e.g. through the
+/// auxiliary vector.
+#[derive(Debug)]
+pub struct LoadedImage {
+    pub entry: VirtualAddress,
+    /// How far the image was moved from the addresses in the file
+    pub bias: usize,
+    /// Where the program headers ended up in memory, if they were loaded at all
+    pub phdr: Option<VirtualAddress>,
+    pub phent: usize,
+    pub phnum: usize,
+}
+
+fn page_down(addr: usize) -> usize {
+    addr / FRAME_SIZE * FRAME_SIZE
+}
+
+fn page_up(addr: usize) -> Option<usize> {
+    Some(page_down(addr.checked_add(FRAME_SIZE - 1)?))
+}
+
+/// Load an executable into the address space of `context`, and point the context at its entry
+/// point.
+pub fn load_elf(file: &[u8], context: &mut ActiveContextHandle) -> Result<LoadedImage, Error> {
     let _guard = tracing::debug_span!("loading elf file").entered();
     let elf = goblin::elf::Elf::parse(file)?;
-    let table = unsafe { context.arch().table() };
-    for program_header in elf.program_headers.iter().filter(|h| h.p_type == 1) {
-        let vm_range = program_header.vm_range();
-        if vm_range.start == 0 {
+    if !elf.is_64 || !elf.little_endian || elf.header.e_machine != EM_AARCH64 {
+        return Err(Error::UnsupportedFormat);
+    }
+    let bias = match elf.header.e_type {
+        ET_EXEC => 0,
+        ET_DYN => PIE_LOAD_BIAS,
+        ty => return Err(Error::UnsupportedType(ty)),
+    };
+
+    let (space, table) = context.memory();
+    // (page-aligned start, page-aligned end, protection) of each segment
+    let mut segments = Vec::new();
+    let mut mapped_end = 0;
+    // where the last segment ended, before rounding up to a page
+    let mut prev_end = 0;
+    for program_header in elf.program_headers.iter().filter(|h| h.p_type == PT_LOAD) {
+        let file_end = program_header.p_offset.checked_add(program_header.p_filesz);
+        if program_header.p_filesz > program_header.p_memsz
+            || file_end.map_or(true, |end| end > file.len() as u64)
+        {
+            return Err(Error::BadSegment);
+        }
+        let file_range = program_header.file_range();
+        if program_header.p_memsz == 0 {
             continue;
         }
 
-        let start = VirtualAddress(vm_range.start);
-        let size = vm_range.end - vm_range.start;
-        tracing::debug!(va=?start, size=?ForceLowerHex(size), "loading program header");
+        // everything here comes from the file, so none of it can be trusted not to overflow
+        let vaddr = (program_header.p_vaddr as usize)
+            .checked_add(bias)
+            .ok_or(Error::BadSegment)?;
+        let mem_end = vaddr
+            .checked_add(program_header.p_memsz as usize)
+            .ok_or(Error::BadSegment)?;
+        let start = page_down(vaddr);
+        let end = page_up(mem_end).ok_or(Error::BadSegment)?;
+        // segments have to be in order and can't overlap, or else a segment could be entirely in
+        // the pages already mapped for another, and not get mapped at all
+        if vaddr < prev_end {
+            return Err(Error::BadSegment);
+        }
+        prev_end = mem_end;
+        tracing::debug!(
+            va = ?VirtualAddress(vaddr),
+            size = ?ForceLowerHex(program_header.p_memsz),
+            "loading program header"
+        );
+
+        let mut prot = Protection::empty();
+        if program_header.p_flags & PF_R != 0 {
+            prot |= Protection::READ;
+        }
+        if program_header.p_flags & PF_W != 0 {
+            prot |= Protection::WRITE;
+        }
+        if program_header.p_flags & PF_X != 0 {
+            prot |= Protection::EXEC;
+        }
+
+        // the last page of one segment can be the first page of the next, in which case it's
+        // already mapped
+        let map_start = core::cmp::max(start, mapped_end);
+        if map_start < end {
+            let backing = Backing::Elf {
+                file_offset: (program_header.p_offset as usize + map_start).wrapping_sub(vaddr),
+            };
+            // map it writable first so that we can fill it in
+            let rw = Protection::READ | Protection::WRITE;
+            space.map(table, VirtualAddress(map_start), end - map_start, rw, backing)?;
+            // fresh frames are full of whatever was there before
+            unsafe { core::ptr::write_bytes(map_start as *mut u8, 0, end - map_start) };
+            mapped_end = end;
+        }
 
-        table.alloc(VirtualAddress(vm_range.start), size).unwrap();
-        let dest = unsafe { core::slice::from_raw_parts_mut(vm_range.start as *mut u8, size) };
+        // anything past p_filesz is bss, which has just been zeroed
+        let dest = unsafe { core::slice::from_raw_parts_mut(vaddr as *mut u8, file_range.len()) };
+        dest.copy_from_slice(&file[file_range]);
 
-        let src = &file[program_header.file_range()];
-        dest.copy_from_slice(src);
+        segments.push((start, end, prot));
     }
 
-    context.set_entry_point(VirtualAddress(elf.entry as usize));
+    if bias != 0 {
+        for reloc in elf.dynrelas.iter() {
+            if reloc.r_type != R_AARCH64_RELATIVE {
+                return Err(Error::UnsupportedRelocation(reloc.r_type));
+            }
+            let addr = (reloc.r_offset as usize)
+                .checked_add(bias)
+                .map(VirtualAddress)
+                .ok_or(Error::BadRelocation)?;
+            // everything is still writable at this point, so go by what the segments will be once
+            // they get their real permissions. being aligned, the value can't cross into a page
+            // that isn't part of the same segment
+            let writable = segments.iter().any(|&(start, end, prot)| {
+                (start..end).contains(&addr.0) && prot.contains(Protection::WRITE)
+            });
+            if addr.0 % 8 != 0 || !writable {
+                return Err(Error::BadRelocation);
+            }
+            let value = (bias as i64).wrapping_add(reloc.r_addend.unwrap_or(0)) as u64;
+            unsafe { (addr.0 as *mut u64).write(value) };
+        }
+    }
+
+    // now everything is in place, set the real permissions. a page shared between two segments
+    // gets the permissions of both, except that it can't be writable and executable, so if one
+    // of them is writable it wins and the page isn't executable
+    let mut prev: Option<(usize, Protection)> = None;
+    for &(mut start, end, prot) in &segments {
+        if let Some((prev_end, prev_prot)) = prev {
+            if prev_end > start {
+                let mut shared = prot | prev_prot;
+                if shared.contains(Protection::WRITE) {
+                    shared.remove(Protection::EXEC);
+                }
+                space.protect(table, VirtualAddress(start), FRAME_SIZE, shared)?;
+                start += FRAME_SIZE;
+            }
+        }
+        if start < end {
+            space.protect(table, VirtualAddress(start), end - start, prot)?;
+        }
+        prev = Some((end, prot));
+    }
+
+    let phdr = elf
+        .program_headers
+        .iter()
+        .find(|h| h.p_type == PT_PHDR)
+        .map(|h| h.p_vaddr as usize)
+        .or_else(|| {
+            // otherwise, see if they happen to be inside a loaded segment
+            let phoff = elf.header.e_phoff;
+            elf.program_headers
+                .iter()
+                .filter(|h| h.p_type == PT_LOAD)
+                .find(|h| h.p_offset <= phoff && phoff - h.p_offset < h.p_filesz)
+                .and_then(|h| h.p_vaddr.checked_add(phoff - h.p_offset))
+                .map(|phdr| phdr as usize)
+        })
+        .and_then(|phdr| phdr.checked_add(bias))
+        .map(VirtualAddress);
+
+    let image = LoadedImage {
+        // a nonsense entry point just faults as soon as the program starts
+        entry: VirtualAddress((elf.header.e_entry as usize).wrapping_add(bias)),
+        bias,
+        phdr,
+        phent: elf.header.e_phentsize as usize,
+        phnum: elf.program_headers.len(),
+    };
+    context.set_entry_point(image.entry);
 
-    Ok(())
+    Ok(image)
 }
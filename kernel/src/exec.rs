@@ -0,0 +1,188 @@
+use crate::{
+    arch::FRAME_SIZE,
+    context::ActiveContextHandle,
+    elf::{self, LoadedImage},
+    vm::{address_space, Backing, Protection, VirtualAddress},
+};
+
+/// The highest address of the initial stack
+const STACK_TOP: VirtualAddress = VirtualAddress(0x0000_0000_8000_0000);
+pub const DEFAULT_STACK_SIZE: usize = 64 * 1024;
+
+// auxiliary vector keys, from the System V ABI
+const AT_NULL: usize = 0;
+const AT_PHDR: usize = 3;
+const AT_PHENT: usize = 4;
+const AT_PHNUM: usize = 5;
+const AT_PAGESZ: usize = 6;
+const AT_BASE: usize = 7;
+const AT_ENTRY: usize = 9;
+const AT_RANDOM: usize = 25;
+const AUXV_LEN: usize = 8;
+
+#[derive(Debug)]
+pub enum Error {
+    Elf(elf::Error),
+    Map(address_space::Error),
+    /// The arguments and environment don't fit on the stack
+    ArgumentsTooLong,
+    /// The stack doesn't fit below STACK_TOP, with a guard page under it
+    StackTooBig,
+}
+
+impl From<elf::Error> for Error {
+    fn from(e: elf::Error) -> Self {
+        Error::Elf(e)
+    }
+}
+
+impl From<address_space::Error> for Error {
+    fn from(e: address_space::Error) -> Self {
+        Error::Map(e)
+    }
+}
+
+/// Load the executable `file` into the context and set up its initial stack, System V style:
+///
+/// ```text
+/// STACK_TOP -> argument and environment strings, AT_RANDOM bytes
+///              padding to 16 bytes
+///              auxiliary vector, ending with AT_NULL
+///              envp, ending with NULL
+///              argv, ending with NULL
+///       sp  -> argc
+/// ```
+///
+/// `stack_size` is rounded up to a whole number of pages, and an inaccessible guard page is
+/// reserved below the stack so that overflowing it faults.
+pub fn exec(
+    context: &mut ActiveContextHandle,
+    file: &[u8],
+    argv: &[&str],
+    envp: &[&str],
+    stack_size: usize,
+) -> Result<(), Error> {
+    let _guard = tracing::debug_span!("exec", ?argv).entered();
+    let image = elf::load_elf(file, context)?;
+
+    let stack_size = stack_size
+        .checked_add(FRAME_SIZE - 1)
+        .map(|size| size / FRAME_SIZE * FRAME_SIZE)
+        .ok_or(Error::StackTooBig)?;
+    let stack_bottom = STACK_TOP
+        .0
+        .checked_sub(stack_size)
+        .filter(|&bottom| bottom >= FRAME_SIZE)
+        .map(VirtualAddress)
+        .ok_or(Error::StackTooBig)?;
+    let (space, table) = context.memory();
+    space.map(
+        table,
+        VirtualAddress(stack_bottom.0 - FRAME_SIZE),
+        FRAME_SIZE,
+        Protection::empty(),
+        Backing::Anonymous,
+    )?;
+    space.map(
+        table,
+        stack_bottom,
+        stack_size,
+        Protection::READ | Protection::WRITE,
+        Backing::Anonymous,
+    )?;
+
+    let strings_size: usize = argv.iter().chain(envp).map(|s| s.len() + 1).sum::<usize>() + 16;
+    let pointers_size = (1 + argv.len() + 1 + envp.len() + 1 + 2 * AUXV_LEN) * 8;
+    // leave at least a page for the program to actually use
+    let usable = stack_size
+        .checked_sub(FRAME_SIZE)
+        .ok_or(Error::ArgumentsTooLong)?;
+    if strings_size + pointers_size + 16 > usable {
+        return Err(Error::ArgumentsTooLong);
+    }
+
+    // the stack is demand paged, so these writes fault the pages in as they go
+    let mut stack = StackWriter { sp: STACK_TOP.0 };
+    let random = stack.push_bytes(&random_bytes());
+    let mut argv_ptrs = alloc::vec::Vec::with_capacity(argv.len());
+    for arg in argv {
+        argv_ptrs.push(stack.push_str(arg));
+    }
+    let mut envp_ptrs = alloc::vec::Vec::with_capacity(envp.len());
+    for var in envp {
+        envp_ptrs.push(stack.push_str(var));
+    }
+
+    let auxv = auxiliary_vector(&image, random);
+    let words = 1 + argv_ptrs.len() + 1 + envp_ptrs.len() + 1 + auxv.len() * 2;
+    // sp has to be 16 byte aligned once everything is pushed
+    stack.sp = (stack.sp - words * 8) / 16 * 16 + words * 8;
+    for &(key, value) in auxv.iter().rev() {
+        stack.push_usize(value);
+        stack.push_usize(key);
+    }
+    stack.push_usize(0);
+    for ptr in envp_ptrs.iter().rev() {
+        stack.push_usize(ptr.0);
+    }
+    stack.push_usize(0);
+    for ptr in argv_ptrs.iter().rev() {
+        stack.push_usize(ptr.0);
+    }
+    stack.push_usize(argv.len());
+
+    context.arch().set_stack_pointer(VirtualAddress(stack.sp));
+    Ok(())
+}
+
+fn auxiliary_vector(image: &LoadedImage, random: VirtualAddress) -> [(usize, usize); AUXV_LEN] {
+    [
+        (AT_PHDR, image.phdr.map(|p| p.0).unwrap_or(0)),
+        (AT_PHENT, image.phent),
+        (AT_PHNUM, image.phnum),
+        (AT_PAGESZ, FRAME_SIZE),
+        // there's no interpreter
+        (AT_BASE, 0),
+        (AT_ENTRY, image.entry.0),
+        (AT_RANDOM, random.0),
+        (AT_NULL, 0),
+    ]
+}
+
+/// 16 bytes for AT_RANDOM. There's no hardware RNG to ask, so these are far from
+/// cryptographically secure; they're just different every time.
+fn random_bytes() -> [u8; 16] {
+    let mut state = crate::arch::timer::counter() | 1;
+    let mut bytes = [0; 16];
+    for chunk in bytes.chunks_mut(8) {
+        // xorshift64
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        chunk.copy_from_slice(&state.to_le_bytes());
+    }
+    bytes
+}
+
+struct StackWriter {
+    sp: usize,
+}
+
+impl StackWriter {
+    fn push_bytes(&mut self, bytes: &[u8]) -> VirtualAddress {
+        self.sp -= bytes.len();
+        let dest = unsafe { core::slice::from_raw_parts_mut(self.sp as *mut u8, bytes.len()) };
+        dest.copy_from_slice(bytes);
+        VirtualAddress(self.sp)
+    }
+
+    fn push_str(&mut self, s: &str) -> VirtualAddress {
+        self.push_bytes(&[0]);
+        self.push_bytes(s.as_bytes())
+    }
+
+    fn push_usize(&mut self, value: usize) {
+        self.sp -= 8;
+        unsafe { (self.sp as *mut usize).write(value) };
+    }
+}
@@ -0,0 +1,79 @@
+// Finding files in the initrd, which is a cpio archive in the "newc" format (what
+// `find . | cpio -o -H newc` produces)
+//
+// For convenience an initrd can also be a bare ELF file, in which case it's treated as an archive
+// containing just init.
+
+static INITRD: spin::Once<&'static [u8]> = spin::Once::new();
+
+const NEWC_MAGIC: &[u8] = b"070701";
+const ELF_MAGIC: &[u8] = b"\x7fELF";
+const HEADER_SIZE: usize = 110;
+const TRAILER: &str = "TRAILER!!!";
+
+/// A file in the initrd.
+pub struct Entry {
+    pub name: &'static str,
+    pub data: &'static [u8],
+}
+
+/// Remember where the initrd is, so that files can be found in it later.
+pub fn init(initrd: &'static [u8]) {
+    INITRD.call_once(|| initrd);
+}
+
+/// The whole initrd, or nothing if [`init`] hasn't been called.
+pub fn get() -> &'static [u8] {
+    INITRD.r#try().copied().unwrap_or(&[])
+}
+
+/// Iterate over the entries in the archive, stopping at the first one that doesn't look right.
+pub fn entries(initrd: &'static [u8]) -> impl Iterator<Item = Entry> {
+    let mut offset = 0;
+    core::iter::from_fn(move || {
+        let header = initrd.get(offset..offset + HEADER_SIZE)?;
+        if &header[..6] != NEWC_MAGIC {
+            return None;
+        }
+        // every field is 8 ascii hex digits
+        let field = |idx: usize| {
+            let digits = core::str::from_utf8(&header[6 + idx * 8..14 + idx * 8]).ok()?;
+            usize::from_str_radix(digits, 16).ok()
+        };
+        let file_size = field(6)?;
+        let name_size = field(11)?;
+
+        let name_start = offset + HEADER_SIZE;
+        // the name includes a nul terminator
+        let name = initrd.get(name_start..name_start + name_size.checked_sub(1)?)?;
+        let name = core::str::from_utf8(name).ok()?;
+        if name == TRAILER {
+            return None;
+        }
+        // the header + name and the data are each padded to 4 bytes
+        let data_start = align4(name_start + name_size);
+        let data = initrd.get(data_start..data_start + file_size)?;
+        offset = align4(data_start + file_size);
+
+        Some(Entry { name, data })
+    })
+}
+
+/// Find the file at `path`, ignoring any leading `/` or `./`.
+pub fn find(initrd: &'static [u8], path: &str) -> Option<&'static [u8]> {
+    if initrd.starts_with(ELF_MAGIC) {
+        return Some(initrd);
+    }
+    let path = normalise(path);
+    entries(initrd)
+        .find(|entry| normalise(entry.name) == path)
+        .map(|entry| entry.data)
+}
+
+fn normalise(path: &str) -> &str {
+    path.trim_start_matches("./").trim_start_matches('/')
+}
+
+fn align4(x: usize) -> usize {
+    (x + 3) & !3
+}
@@ -7,18 +7,22 @@
 #![feature(new_uninit)]
 #![feature(panic_info_message)]
 #![feature(ptr_as_uninit)]
-#![feature(pointer_is_aligned)]
 
-use crate::context::{Context, CONTEXTS};
+use alloc::vec::Vec;
+
+use crate::context::Context;
 
 extern crate alloc;
 
 mod arch;
+mod cmdline;
 #[macro_use]
 mod console;
 mod context;
 mod elf;
+mod exec;
 mod fmt;
+mod initrd;
 mod memory;
 mod panic;
 mod syscall;
@@ -26,14 +30,26 @@ mod tracing;
 mod vm;
 
 pub fn main(arch: arch::Arch) {
-    let context = unsafe { &mut CONTEXTS }
-        .entry(0)
-        .or_insert(Context::new(0))
-        .as_ref();
+    let context = context::insert(Context::new(0, None));
+    context::start_reaper();
+    context::sched::create_idle_thread(0);
+    arch::smp::start_secondaries(&arch);
+    let cmdline = cmdline::get();
+    initrd::init(arch.initrd);
+    let init = initrd::find(initrd::get(), cmdline.init)
+        .unwrap_or_else(|| panic!("couldn't find {} in the initrd", cmdline.init));
+    let mut argv = Vec::from([cmdline.init]);
+    argv.extend(cmdline.init_args());
+
     let mut active = unsafe { context.enter() };
-    active.arch().init();
-    elf::load_elf(arch.initrd, &mut active).unwrap();
+    active.init();
+    if let Err(err) = exec::exec(&mut active, init, &argv, &[], exec::DEFAULT_STACK_SIZE) {
+        // the crate root's `tracing` is our module, not the crate
+        ::tracing::error!(?err, init = cmdline.init, "couldn't start init");
+        arch::platform::exit(1);
+    }
 
+    arch::timer::start_slice();
     unsafe { active.jump_to_userspace() };
 
     panic!("end of main");
@@ -0,0 +1,210 @@
+// Per-frame metadata, roughly equivalent to linux's struct page
+
+use core::{
+    mem::size_of,
+    sync::atomic::{AtomicU32, AtomicU8, Ordering},
+};
+
+use bitflags::bitflags;
+
+use crate::{
+    arch::{vm::phys_to_virt, FRAME_SIZE},
+    vm::PhysicalAddress,
+};
+
+static FRAME_TABLE: spin::Once<FrameTable> = spin::Once::new();
+
+/// What a frame is being used for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum FrameOwner {
+    Free = 0,
+    /// Allocated by the kernel for something not covered below, or never given to the allocator
+    Kernel = 1,
+    PageTable = 2,
+    UserAnon = 3,
+    KernelHeap = 4,
+    Dma = 5,
+}
+
+impl FrameOwner {
+    const ALL: [FrameOwner; 6] = [
+        FrameOwner::Free,
+        FrameOwner::Kernel,
+        FrameOwner::PageTable,
+        FrameOwner::UserAnon,
+        FrameOwner::KernelHeap,
+        FrameOwner::Dma,
+    ];
+
+    fn from_u8(value: u8) -> Self {
+        Self::ALL[value as usize]
+    }
+}
+
+bitflags! {
+    pub struct FrameFlags: u8 {
+        /// The frame was not part of a free region when the frame table was built, e.g. it holds
+        /// the kernel image or the device tree
+        const RESERVED = 1 << 0;
+    }
+}
+
+pub struct FrameInfo {
+    refcount: AtomicU32,
+    owner: AtomicU8,
+    flags: AtomicU8,
+    /// The order of the free block the frame is the head of, or NOT_FREE if it isn't one
+    free_order: AtomicU8,
+}
+
+const NOT_FREE: u8 = u8::MAX;
+
+impl FrameInfo {
+    const fn new_reserved() -> Self {
+        FrameInfo {
+            refcount: AtomicU32::new(1),
+            owner: AtomicU8::new(FrameOwner::Kernel as u8),
+            flags: AtomicU8::new(FrameFlags::RESERVED.bits()),
+            free_order: AtomicU8::new(NOT_FREE),
+        }
+    }
+
+    pub fn refcount(&self) -> u32 {
+        self.refcount.load(Ordering::Relaxed)
+    }
+
+    /// Take another reference to the frame, e.g. when mapping it a second time.
+    pub fn get_ref(&self) {
+        let old = self.refcount.fetch_add(1, Ordering::Relaxed);
+        assert_ne!(old, 0, "took a reference to a free frame");
+    }
+
+    /// Drop a reference to the frame, returning true if that was the last one.
+    pub fn put_ref(&self) -> bool {
+        let old = self.refcount.fetch_sub(1, Ordering::Release);
+        assert_ne!(old, 0, "frame refcount underflow");
+        old == 1
+    }
+
+    pub fn owner(&self) -> FrameOwner {
+        FrameOwner::from_u8(self.owner.load(Ordering::Relaxed))
+    }
+
+    pub fn set_owner(&self, owner: FrameOwner) {
+        self.owner.store(owner as u8, Ordering::Relaxed);
+    }
+
+    pub fn flags(&self) -> FrameFlags {
+        FrameFlags::from_bits_truncate(self.flags.load(Ordering::Relaxed))
+    }
+
+    pub fn set_flags(&self, flags: FrameFlags) {
+        self.flags.store(flags.bits(), Ordering::Relaxed);
+    }
+
+    /// The order of the free block starting at this frame, if there is one. This is what the frame
+    /// allocator goes by, since the frame itself could hold anything.
+    pub(super) fn free_order(&self) -> Option<usize> {
+        match self.free_order.load(Ordering::Relaxed) {
+            NOT_FREE => None,
+            order => Some(order as usize),
+        }
+    }
+
+    /// Called by the frame allocator when a free block starting at this frame goes on a free list
+    /// (`Some(order)`) or comes off one (`None`).
+    pub(super) fn set_free_order(&self, order: Option<usize>) {
+        let order = order.map_or(NOT_FREE, |order| order as u8);
+        self.free_order.store(order, Ordering::Relaxed);
+    }
+
+    /// Called by the frame allocator when it hands the frame out.
+    pub(super) fn on_alloc(&self) {
+        self.refcount.store(1, Ordering::Relaxed);
+        self.owner.store(FrameOwner::Kernel as u8, Ordering::Relaxed);
+        self.flags.store(0, Ordering::Relaxed);
+    }
+
+    /// Called by the frame allocator when the frame is given back to it.
+    pub(super) fn on_dealloc(&self) {
+        self.refcount.store(0, Ordering::Relaxed);
+        self.owner.store(FrameOwner::Free as u8, Ordering::Relaxed);
+        self.flags.store(0, Ordering::Relaxed);
+    }
+}
+
+struct FrameTable {
+    /// The frame number of the first frame described by the table
+    first_frame: usize,
+    frames: &'static [FrameInfo],
+}
+
+/// The number of bytes of storage needed for the metadata of `start..end`.
+pub fn table_size(start: PhysicalAddress, end: PhysicalAddress) -> usize {
+    let frames = (end.0 - start.0 + FRAME_SIZE - 1) / FRAME_SIZE;
+    let bytes = frames * size_of::<FrameInfo>();
+    (bytes + FRAME_SIZE - 1) / FRAME_SIZE * FRAME_SIZE
+}
+
+/// Build the frame table covering `start..end`, storing it at `storage`, which must be at least
+/// [`table_size`] bytes long and not used for anything else.
+///
+/// Every frame starts out reserved; frames become free as they are given to the frame allocator.
+/// This should therefore be called before the allocator is told about any memory other than the
+/// early spare frames.
+pub fn init(start: PhysicalAddress, end: PhysicalAddress, storage: PhysicalAddress) {
+    let first_frame = start.0 / FRAME_SIZE;
+    let count = (end.0 - start.0 + FRAME_SIZE - 1) / FRAME_SIZE;
+    let base = phys_to_virt(storage).0 as *mut FrameInfo;
+    let frames = unsafe {
+        for i in 0..count {
+            base.add(i).write(FrameInfo::new_reserved());
+        }
+        core::slice::from_raw_parts(base, count)
+    };
+    FRAME_TABLE.call_once(|| FrameTable {
+        first_frame,
+        frames,
+    });
+    tracing::debug!(frames = count, "built frame table");
+}
+
+/// Get the metadata for the frame containing `phys`, or None if it isn't covered by the frame
+/// table (or the table hasn't been built yet).
+pub fn get(phys: PhysicalAddress) -> Option<&'static FrameInfo> {
+    let table = FRAME_TABLE.r#try()?;
+    let idx = (phys.0 / FRAME_SIZE).checked_sub(table.first_frame)?;
+    table.frames.get(idx)
+}
+
+pub fn set_owner(phys: PhysicalAddress, owner: FrameOwner) {
+    if let Some(info) = get(phys) {
+        info.set_owner(owner);
+    }
+}
+
+/// Drop a reference to the frame at `phys`, giving it back to the frame allocator if it was the
+/// last one.
+pub fn put(phys: PhysicalAddress) {
+    let last = match get(phys) {
+        Some(info) => info.put_ref(),
+        // no metadata means no sharing, so this must be the only reference
+        None => true,
+    };
+    if last {
+        super::FRAME_ALLOCATOR.lock().dealloc(phys);
+    }
+}
+
+/// Count the frames belonging to each kind of owner, indexed by `FrameOwner as usize`. Useful for
+/// spotting leaks.
+pub fn usage() -> [(FrameOwner, usize); 6] {
+    let mut counts = FrameOwner::ALL.map(|owner| (owner, 0));
+    if let Some(table) = FRAME_TABLE.r#try() {
+        for frame in table.frames {
+            counts[frame.owner() as usize].1 += 1;
+        }
+    }
+    counts
+}
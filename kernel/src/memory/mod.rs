@@ -0,0 +1,313 @@
+use crate::{
+    arch::{vm::phys_to_virt, FRAME_SIZE},
+    vm::PhysicalAddress,
+};
+use linked_list_allocator::LockedHeap;
+
+pub mod frame;
+
+#[global_allocator]
+pub static KERNEL_HEAP_ALLOCATOR: LockedHeap = LockedHeap::empty();
+
+pub static FRAME_ALLOCATOR: spin::Mutex<FrameAllocator> = spin::Mutex::new(FrameAllocator::empty());
+
+#[alloc_error_handler]
+pub fn alloc_error_handler(_: core::alloc::Layout) -> ! {
+    loop {
+        unsafe {
+            core::arch::asm!("wfi");
+        }
+    }
+}
+
+/// The number of block sizes the frame allocator deals in. The biggest block is
+/// `FRAME_SIZE << (MAX_ORDER - 1)` bytes, which is 4 MiB with 4 KiB frames.
+pub const MAX_ORDER: usize = 11;
+
+/// A binary buddy allocator for physical frames.
+///
+/// Free blocks of `FRAME_SIZE << order` bytes are kept in one doubly linked list per order. The
+/// list nodes live inside the free blocks themselves (accessed through the direct map), so the
+/// allocator needs no heap and can be used before the kernel heap exists.
+pub struct FrameAllocator {
+    free_lists: [Option<PhysicalAddress>; MAX_ORDER],
+    free_frames: usize,
+}
+
+#[repr(C)]
+struct FreeBlock {
+    prev: Option<PhysicalAddress>,
+    next: Option<PhysicalAddress>,
+}
+
+pub struct Chunk {
+    pub phys: PhysicalAddress,
+    pub size: usize,
+}
+
+const fn block_size(order: usize) -> usize {
+    FRAME_SIZE << order
+}
+
+fn block_ptr(phys: PhysicalAddress) -> *mut FreeBlock {
+    phys_to_virt(phys).0 as *mut FreeBlock
+}
+
+impl FrameAllocator {
+    pub const fn empty() -> Self {
+        Self {
+            free_lists: [None; MAX_ORDER],
+            free_frames: 0,
+        }
+    }
+
+    /// Give the range `start..start + size` to the allocator. Partial frames at either end are
+    /// ignored.
+    pub fn insert_hole(&mut self, start: PhysicalAddress, size: usize) {
+        let end = (start.0 + size) / FRAME_SIZE * FRAME_SIZE;
+        let mut start = (start.0 + FRAME_SIZE - 1) / FRAME_SIZE * FRAME_SIZE;
+        if start >= end {
+            return;
+        }
+
+        // Carve the range into the biggest naturally aligned blocks that fit
+        while start < end {
+            let mut order = MAX_ORDER - 1;
+            while start % block_size(order) != 0 || start + block_size(order) > end {
+                order -= 1;
+            }
+            self.dealloc_order(PhysicalAddress(start), order);
+            start += block_size(order);
+        }
+    }
+
+    /// The number of frames that are currently free.
+    pub fn free_frames(&self) -> usize {
+        self.free_frames
+    }
+
+    pub fn alloc(&mut self) -> PhysicalAddress {
+        self.alloc_order(0).expect("out of physical memory")
+    }
+
+    /// Allocate `1 << order` contiguous frames, aligned to their own size.
+    pub fn alloc_order(&mut self, order: usize) -> Option<PhysicalAddress> {
+        assert!(order < MAX_ORDER);
+        let mut current = order;
+        while self.free_lists[current].is_none() {
+            current += 1;
+            if current == MAX_ORDER {
+                return None;
+            }
+        }
+
+        let phys = self.pop(current).unwrap();
+        // Split the block in half until it's the right size, freeing the upper halves
+        while current > order {
+            current -= 1;
+            self.push(phys + block_size(current), current);
+        }
+        self.free_frames -= 1 << order;
+        for i in 0..1 << order {
+            if let Some(info) = frame::get(phys + i * FRAME_SIZE) {
+                info.on_alloc();
+            }
+        }
+        Some(phys)
+    }
+
+    /// Allocate up to `size` bytes of contiguous memory. The returned chunk may be smaller than
+    /// requested if there is no free block big enough, so callers should keep allocating until
+    /// they have what they need.
+    pub fn alloc_range(&mut self, size: usize) -> Chunk {
+        let frames = core::cmp::max((size + FRAME_SIZE - 1) / FRAME_SIZE, 1);
+        let mut order = core::cmp::min(frames.ilog2() as usize, MAX_ORDER - 1);
+        loop {
+            if let Some(phys) = self.alloc_order(order) {
+                return Chunk {
+                    phys,
+                    size: block_size(order),
+                };
+            }
+            order = order.checked_sub(1).expect("out of physical memory");
+        }
+    }
+
+    pub fn dealloc(&mut self, phys: PhysicalAddress) {
+        self.dealloc_order(phys, 0);
+    }
+
+    /// Free a block previously returned by [`alloc_order`](Self::alloc_order) with the same
+    /// `order`.
+    pub fn dealloc_order(&mut self, mut phys: PhysicalAddress, mut order: usize) {
+        assert!(phys.0 % block_size(order) == 0, "misaligned block freed");
+        self.free_frames += 1 << order;
+        for i in 0..1 << order {
+            if let Some(info) = frame::get(phys + i * FRAME_SIZE) {
+                info.on_dealloc();
+            }
+        }
+
+        // Merge with the buddy for as long as it's free too
+        while order < MAX_ORDER - 1 {
+            let buddy = PhysicalAddress(phys.0 ^ block_size(order));
+            if !self.is_free(buddy, order) {
+                break;
+            }
+            self.remove(buddy, order);
+            phys = PhysicalAddress(phys.0 & !block_size(order));
+            order += 1;
+        }
+        self.push(phys, order);
+    }
+
+    /// Whether the block at `phys` is the head of a free block of exactly `order`.
+    fn is_free(&self, phys: PhysicalAddress, order: usize) -> bool {
+        // Used frames can contain anything, including whatever userspace likes if they're mapped
+        // there, so nothing in the block itself can be trusted. Frames without any metadata are
+        // never merged with their buddies.
+        frame::get(phys).map_or(false, |info| info.free_order() == Some(order))
+    }
+
+    fn push(&mut self, phys: PhysicalAddress, order: usize) {
+        let head = self.free_lists[order];
+        unsafe {
+            block_ptr(phys).write(FreeBlock {
+                prev: None,
+                next: head,
+            });
+            if let Some(head) = head {
+                (*block_ptr(head)).prev = Some(phys);
+            }
+        }
+        self.free_lists[order] = Some(phys);
+        if let Some(info) = frame::get(phys) {
+            info.set_free_order(Some(order));
+        }
+    }
+
+    fn pop(&mut self, order: usize) -> Option<PhysicalAddress> {
+        let head = self.free_lists[order]?;
+        self.remove(head, order);
+        Some(head)
+    }
+
+    fn remove(&mut self, phys: PhysicalAddress, order: usize) {
+        unsafe {
+            let FreeBlock { prev, next } = block_ptr(phys).read();
+            match prev {
+                Some(prev) => (*block_ptr(prev)).next = next,
+                None => self.free_lists[order] = next,
+            }
+            if let Some(next) = next {
+                (*block_ptr(next)).prev = prev;
+            }
+        }
+        if let Some(info) = frame::get(phys) {
+            info.set_free_order(None);
+        }
+    }
+}
+
+/*
+pub struct IntrusiveLinkedListAllocator {
+    head: Option<NonNull<Hole>>,
+}
+
+pub struct Hole {
+    size: usize,
+    next: Option<NonNull<Hole>>,
+}
+
+impl IntrusiveLinkedListAllocator {
+    pub const fn new() -> Self {
+        Self {
+            head: None,
+        }
+    }
+
+    pub unsafe fn insert_hole(&mut self, address: usize, size: usize) {
+        assert_ne!(address, 0);
+        assert!(size > core::mem::size_of::<Hole>());
+        let mut new_hole_ptr = NonNull::new(address as *mut Hole).unwrap();
+        match self.head {
+            Some(head) => {
+                // If the new hole is before the current head, the new hole becomes the head
+                if address < head.as_ptr() as usize {
+                    assert!(address + size <= head.as_ptr() as usize);
+                    new_hole_ptr.as_uninit_mut().write(Hole {
+                        size,
+                        next: Some(head),
+                    });
+                    self.head = Some(new_hole_ptr);
+                    return;
+                }
+
+                // Find a hole which starts after the new one, and insert the new one before it
+                let mut current = head;
+                while let Some(next) = current.as_ref().next {
+                    let next_addr = next.as_ptr() as usize;
+                    if next_addr > address {
+                        assert!(current.as_ptr() as usize + current.as_ref().size <= address);
+                        assert!(address + size <= next_addr);
+                        new_hole_ptr.as_uninit_mut().write(Hole {
+                            size,
+                            next: Some(next),
+                        });
+                        current.as_mut().next = Some(new_hole_ptr);
+                        return;
+                    }
+                    current = next;
+                }
+
+                // The loop has finished; the new hole is past the end of all the existing ones
+                assert!(current.as_ptr() as usize + current.as_ref().size <= address);
+                new_hole_ptr.as_uninit_mut().write(Hole {
+                    size,
+                    next: None,
+                });
+                current.as_mut().next = Some(new_hole_ptr);
+            },
+            None => {
+                new_hole_ptr.as_uninit_mut().write(Hole {
+                    size,
+                    next: None,
+                });
+                self.head = Some(new_hole_ptr);
+            },
+        }
+    }
+
+    pub fn alloc(&mut self, size: usize, align: usize) -> Option<NonNull<()>> {
+        let mut current = self.head.unwrap();
+        while let Some(mut next) = unsafe { current.as_ref() }.next {
+            let next_addr = next.as_ptr() as usize;
+            let aligned = (next_addr + align - 1) / align * align;
+            let difference = aligned - next_addr;
+            if unsafe { next.as_ref() }.size - difference >= size {
+                unsafe {
+                    let old_size = next.as_ref().size;
+                    let old_next = next.as_ref().next;
+                    let mut new_hole_ptr = NonNull::new((aligned + size) as *mut Hole).unwrap();
+                    new_hole_ptr.as_uninit_mut().write(Hole {
+                        size: old_size - difference - size,
+                        next: old_next,
+                    });
+                    // Determine whether to keep the hole `next` or remove it entirely
+                    // Note that if `0 < difference < size_of::<Hole>()` then we are losing memory
+                    // to the void, but it's only a couple of bytes so I don't care
+                    if difference > core::mem::size_of::<Hole>() {
+                        next.as_mut().size = difference;
+                        next.as_mut().next = Some(new_hole_ptr);
+                    } else {
+                        current.as_mut().next = Some(new_hole_ptr);
+                    }
+                    return Some(NonNull::new(aligned as *mut _).unwrap());
+                }
+            }
+            break;
+        }
+        None
+    }
+}
+*/
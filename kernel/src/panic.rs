@@ -18,6 +18,10 @@ pub fn rust_begin_unwind(info: &core::panic::PanicInfo) -> ! {
         println!("panic message: {:#?}", message);
     }
 
+    if crate::cmdline::get().test_mode {
+        crate::arch::platform::exit(101);
+    }
+
     loop {
         unsafe {
             core::arch::asm!("wfi");
@@ -1,66 +1,244 @@
-use crate::context::{ActiveContextHandle, CONTEXTS, SCHED_QUEUE};
+use abi::{Error, Syscall};
 
-#[derive(Debug)]
-#[repr(usize)]
-enum Error {
-    InvalidPointer = 1,
-}
+use crate::{
+    arch::FRAME_SIZE,
+    context::{sched, ActiveContextHandle},
+    initrd,
+    vm::{
+        address_space,
+        user::{self, copy_from_user, copy_to_user, strncpy_from_user, InvalidAddress},
+        Backing, Protection, VirtualAddress,
+    },
+};
 
-fn user_pointer<T>(p: *const T) -> Result<(), Error> {
-    // TODO: is the pointer a valid VA for reads
-    if p as usize > 0x0000_ffff_ffff_ffff || !p.is_aligned() {
-        return Err(Error::InvalidPointer);
-    }
+/// The longest path that can be passed to a syscall, including the nul
+const PATH_MAX: usize = 256;
 
-    Ok(())
+impl From<InvalidAddress> for Error {
+    fn from(_: InvalidAddress) -> Self {
+        Error::InvalidPointer
+    }
 }
 
-fn user_slice<'a, T>(base: *const T, len: usize) -> Result<&'a [T], Error> {
-    // TODO: is the pointer a valid VA for len reads
-    user_pointer(base)?;
-    Ok(unsafe { core::slice::from_raw_parts(base, len) })
+impl From<address_space::Error> for Error {
+    fn from(e: address_space::Error) -> Self {
+        match e {
+            address_space::Error::NotMapped | address_space::Error::AccessDenied => {
+                Error::InvalidPointer
+            }
+            address_space::Error::Table => Error::OutOfMemory,
+            _ => Error::InvalidArgument,
+        }
+    }
 }
 
-pub fn dispatch(num: usize, mut cx_handle: ActiveContextHandle) {
-    // lol
-    let [ref mut a, ref mut b, ref mut _c, ref mut _d, ref mut _e, ref mut _f, ref mut _g, ref mut h] =
-        cx_handle.arch().syscall_params();
-    let res = match num {
-        0 => syscall_exit(),
-        1 => syscall_print(*a as _, *b),
-        2 => syscall_yield(cx_handle),
-        _ => {
-            tracing::warn!("invalid syscall number {a}");
-            syscall_exit();
+pub fn dispatch(mut cx_handle: ActiveContextHandle) {
+    let num = cx_handle.arch().syscall_number();
+    let [a, b, c, d, _e, _f] = *cx_handle.arch().syscall_params();
+    let res = match Syscall::from_number(num) {
+        Some(Syscall::Exit) => syscall_exit(cx_handle, a as i32),
+        Some(Syscall::Print) => syscall_print(&mut cx_handle, VirtualAddress(a), b).map(|()| 0),
+        Some(Syscall::Yield) => syscall_yield(cx_handle),
+        Some(Syscall::Spawn) => syscall_spawn(&mut cx_handle, VirtualAddress(a)),
+        Some(Syscall::Wait) => syscall_wait(&mut cx_handle, a),
+        Some(Syscall::Fork) => syscall_fork(&mut cx_handle),
+        Some(Syscall::Map) => syscall_map(&mut cx_handle, VirtualAddress(a), b, c, d),
+        Some(Syscall::Unmap) => syscall_unmap(&mut cx_handle, VirtualAddress(a), b).map(|()| 0),
+        Some(Syscall::Protect) => {
+            syscall_protect(&mut cx_handle, VirtualAddress(a), b, c).map(|()| 0)
+        }
+        Some(Syscall::Reboot) => syscall_reboot(&mut cx_handle, a),
+        Some(Syscall::Read) => syscall_read(&mut cx_handle, VirtualAddress(a), b),
+        None => {
+            tracing::debug!("invalid syscall number {num}");
+            Err(Error::InvalidSyscall)
         }
     };
-    *h = match res {
-        Ok(_) => 0,
-        Err(e) => e as usize,
-    };
+    set_result(&mut cx_handle, res);
     core::mem::forget(cx_handle);
 }
 
-#[tracing::instrument(level = "debug")]
-fn syscall_exit() -> ! {
-    crate::context::exit();
+fn set_result(cx_handle: &mut ActiveContextHandle, res: Result<usize, Error>) {
+    cx_handle.arch().syscall_params()[0] = abi::encode_result(res);
 }
 
-#[tracing::instrument(level = "debug", err(Debug))]
-fn syscall_print(base: *const u8, len: usize) -> Result<(), Error> {
-    let bytes = user_slice(base, len)?;
-    let bytes = unsafe { core::slice::from_raw_parts(base, len) };
-    let putchar = crate::console::get_writer().0;
-    for byte in bytes {
-        putchar(*byte);
+#[tracing::instrument(level = "debug", skip(cx_handle))]
+fn syscall_exit(cx_handle: ActiveContextHandle, code: i32) -> ! {
+    crate::context::exit(cx_handle, code);
+}
+
+#[tracing::instrument(level = "debug", skip(cx_handle), err(Debug))]
+fn syscall_print(
+    cx_handle: &mut ActiveContextHandle,
+    base: VirtualAddress,
+    len: usize,
+) -> Result<(), Error> {
+    let mut buf = [0; 256];
+    let mut offset = 0;
+    while offset < len {
+        let chunk = core::cmp::min(len - offset, buf.len());
+        let src = VirtualAddress(base.0.checked_add(offset).ok_or(Error::InvalidPointer)?);
+        copy_from_user(cx_handle, &mut buf[..chunk], src)?;
+        // not held over the copy, since faulting pages in can print things
+        let writer = crate::console::get_writer();
+        for byte in &buf[..chunk] {
+            (writer.0)(*byte);
+        }
+        offset += chunk;
     }
     Ok(())
 }
 
+/// Read up to `len` bytes of console input into `base`, waiting for some if there isn't any, and
+/// return how many there were.
+#[tracing::instrument(level = "debug", skip(cx_handle), err(Debug))]
+fn syscall_read(
+    cx_handle: &mut ActiveContextHandle,
+    base: VirtualAddress,
+    len: usize,
+) -> Result<usize, Error> {
+    let mut buf = [0; 256];
+    let len = core::cmp::min(len, buf.len());
+    // whatever gets read is gone from the console, so make sure there's somewhere to put it first
+    user::check(cx_handle, base, len, Protection::WRITE)?;
+    let read = crate::console::read(cx_handle, &mut buf[..len]);
+    copy_to_user(cx_handle, base, &buf[..read])?;
+    Ok(read)
+}
+
 #[tracing::instrument(level = "debug", skip_all)]
 fn syscall_yield(old_active: ActiveContextHandle) -> ! {
-    SCHED_QUEUE.try_insert(old_active.context().id);
-    let new_id = SCHED_QUEUE.try_get().unwrap();
-    let mut new_active = old_active.switch_to(unsafe { &mut CONTEXTS }.get(&new_id).unwrap());
-    unsafe { new_active.jump_to_userspace() }
+    sched::schedule(old_active)
+}
+
+/// Start running the executable at `path` in the initrd, and return its process id. `path` is
+/// nul-terminated.
+#[tracing::instrument(level = "debug", skip(cx_handle), err(Debug))]
+fn syscall_spawn(
+    cx_handle: &mut ActiveContextHandle,
+    path: VirtualAddress,
+) -> Result<usize, Error> {
+    // the caller's memory isn't mapped while the child is being set up, so take a copy
+    let mut buf = [0; PATH_MAX];
+    let len = strncpy_from_user(cx_handle, &mut buf, path)?;
+    if len == PATH_MAX {
+        return Err(Error::NotFound);
+    }
+    let path = core::str::from_utf8(&buf[..len]).map_err(|_| Error::NotFound)?;
+    let file = initrd::find(initrd::get(), path).ok_or(Error::NotFound)?;
+    crate::context::spawn(cx_handle, file, &[path]).map_err(|e| {
+        tracing::debug!(?e, "couldn't spawn");
+        Error::InvalidExecutable
+    })
+}
+
+/// Wait for the child process `pid` to exit, and return its exit code. The code is zero extended
+/// so that negative ones can't be mistaken for errors.
+#[tracing::instrument(level = "debug", skip(cx_handle))]
+fn syscall_wait(cx_handle: &mut ActiveContextHandle, pid: usize) -> Result<usize, Error> {
+    match crate::context::wait(cx_handle, pid) {
+        Some(status) => Ok(status.code as u32 as usize),
+        None => Err(Error::NoSuchProcess),
+    }
+}
+
+/// Make a copy of the calling process. Returns the child's process id in the parent, and 0 in
+/// the child.
+#[tracing::instrument(level = "debug", skip(cx_handle), err(Debug))]
+fn syscall_fork(cx_handle: &mut ActiveContextHandle) -> Result<usize, Error> {
+    crate::context::fork(cx_handle, |child| set_result(child, Ok(0))).map_err(|e| {
+        tracing::debug!(?e, "couldn't fork");
+        Error::OutOfMemory
+    })
+}
+
+/// Map `len` bytes of zeroed memory, at `addr` if possible, and return where it ended up.
+#[tracing::instrument(level = "debug", skip(cx_handle), err(Debug))]
+fn syscall_map(
+    cx_handle: &mut ActiveContextHandle,
+    addr: VirtualAddress,
+    len: usize,
+    prot: usize,
+    flags: usize,
+) -> Result<usize, Error> {
+    let prot = protection(prot)?;
+    if flags & !abi::map::FIXED != 0 {
+        return Err(Error::InvalidArgument);
+    }
+    let fixed = flags & abi::map::FIXED != 0;
+    // the first page stays unmapped to catch null pointers, like in find_free
+    if fixed && addr.0 < FRAME_SIZE {
+        return Err(Error::InvalidArgument);
+    }
+    let len = page_align(len)?;
+
+    let (space, table) = cx_handle.memory();
+    if fixed || addr.0 != 0 {
+        match space.map(table, addr, len, prot, Backing::Anonymous) {
+            Ok(()) => return Ok(addr.0),
+            Err(e) if fixed => return Err(e.into()),
+            // it was only a hint, so go somewhere else
+            Err(_) => {}
+        }
+    }
+    let start = space.find_free(len).ok_or(Error::OutOfMemory)?;
+    space.map(table, start, len, prot, Backing::Anonymous)?;
+    Ok(start.0)
+}
+
+/// Unmap every page in `addr..addr + len`, which doesn't all have to be mapped.
+#[tracing::instrument(level = "debug", skip(cx_handle), err(Debug))]
+fn syscall_unmap(
+    cx_handle: &mut ActiveContextHandle,
+    addr: VirtualAddress,
+    len: usize,
+) -> Result<(), Error> {
+    let len = page_align(len)?;
+    let (space, table) = cx_handle.memory();
+    space.unmap(table, addr, len)?;
+    Ok(())
+}
+
+/// Change the permissions of every page in `addr..addr + len`, which all has to be mapped.
+#[tracing::instrument(level = "debug", skip(cx_handle), err(Debug))]
+fn syscall_protect(
+    cx_handle: &mut ActiveContextHandle,
+    addr: VirtualAddress,
+    len: usize,
+    prot: usize,
+) -> Result<(), Error> {
+    let prot = protection(prot)?;
+    let len = page_align(len)?;
+    let (space, table) = cx_handle.memory();
+    space.protect(table, addr, len, prot)?;
+    Ok(())
+}
+
+fn protection(prot: usize) -> Result<Protection, Error> {
+    u8::try_from(prot)
+        .ok()
+        .and_then(Protection::from_bits)
+        .ok_or(Error::InvalidArgument)
+}
+
+/// Round a nonzero length up to a whole number of pages.
+fn page_align(len: usize) -> Result<usize, Error> {
+    match len.div_ceil(FRAME_SIZE).checked_mul(FRAME_SIZE) {
+        Some(len) if len != 0 => Ok(len),
+        _ => Err(Error::InvalidArgument),
+    }
+}
+
+/// Power the machine off or restart it. Only init can do this, since it's in charge of everything
+/// else.
+#[tracing::instrument(level = "debug", skip(cx_handle), err(Debug))]
+fn syscall_reboot(cx_handle: &mut ActiveContextHandle, how: usize) -> Result<usize, Error> {
+    if cx_handle.context().id != 0 {
+        return Err(Error::NotPermitted);
+    }
+    match how {
+        abi::reboot::POWER_OFF => crate::arch::platform::shutdown(),
+        abi::reboot::RESTART => crate::arch::platform::reboot(),
+        _ => Err(Error::InvalidArgument),
+    }
 }
@@ -1,6 +1,7 @@
 use core::{
+    fmt::Write,
     num::NonZeroU64,
-    sync::atomic::{AtomicBool, AtomicU64, Ordering::Relaxed},
+    sync::atomic::{AtomicU64, Ordering::Relaxed},
 };
 
 use alloc::{collections::BTreeMap, string::String};
@@ -8,7 +9,7 @@ use spin::Mutex;
 use tracing::{span, Metadata, Subscriber};
 use tracing_core::span::Current;
 
-pub static RESET: AtomicBool = AtomicBool::new(false);
+use crate::{arch::percpu, console::Writer};
 
 struct Span {
     metadata: &'static Metadata<'static>,
@@ -30,22 +31,32 @@ impl tracing::field::Visit for DebugVisitor {
     }
 }
 
-struct PrintVisitor;
+struct PrintVisitor<'a>(&'a mut Writer);
 
-impl tracing::field::Visit for PrintVisitor {
+impl tracing::field::Visit for PrintVisitor<'_> {
     fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn core::fmt::Debug) {
         // we kind of assume message gets recorded first otherwise it looks ugly
-        match field.name() {
-            "message" => print!("{:?} ", value),
-            x => print!("{}={:?}, ", x, value),
-        }
+        let _ = match field.name() {
+            "message" => write!(self.0, "{:?} ", value),
+            x => write!(self.0, "{}={:?}, ", x, value),
+        };
     }
 }
 
+/// Forget about whatever span this CPU is in, e.g. because the stack it was entered on has been
+/// thrown away and it'll never be exited.
+pub fn reset_current_span() {
+    current_span().store(0, Relaxed);
+}
+
+/// The span this CPU is in. Each CPU has its own, since they're all off doing different things.
+fn current_span() -> &'static AtomicU64 {
+    &percpu::this().current_span
+}
+
 pub struct PutcharSubscriber {
     spans: Mutex<BTreeMap<u64, Span>>,
     next: AtomicU64,
-    current_span: AtomicU64,
 }
 
 impl PutcharSubscriber {
@@ -53,23 +64,17 @@ impl PutcharSubscriber {
         PutcharSubscriber {
             spans: spin::Mutex::new(BTreeMap::new()),
             next: AtomicU64::new(1),
-            current_span: AtomicU64::new(0),
         }
     }
 
     fn get_current_span(&self) -> Option<span::Id> {
-        if RESET.load(Relaxed) {
-            self.current_span.store(0, Relaxed);
-            RESET.store(false, Relaxed);
-            return None;
-        }
-        NonZeroU64::new(self.current_span.load(Relaxed)).map(span::Id::from_non_zero_u64)
+        NonZeroU64::new(current_span().load(Relaxed)).map(span::Id::from_non_zero_u64)
     }
 }
 
 impl Subscriber for PutcharSubscriber {
-    fn enabled(&self, _: &tracing::Metadata<'_>) -> bool {
-        true
+    fn enabled(&self, metadata: &tracing::Metadata<'_>) -> bool {
+        crate::cmdline::get().log_enabled(metadata)
     }
 
     fn new_span(&self, attrs: &span::Attributes<'_>) -> span::Id {
@@ -106,42 +111,50 @@ impl Subscriber for PutcharSubscriber {
     fn event(&self, event: &tracing::Event<'_>) {
         let spans = self.spans.lock();
         let id = event.parent().cloned().or(self.get_current_span());
-        match id {
+        // the whole event goes out in one go, so other CPUs can't get in the middle of it
+        let mut writer = crate::console::get_writer();
+        let _ = match id {
             Some(id) => {
-                fn print_span_with_parents(spans: &BTreeMap<u64, Span>, span: &Span) {
+                fn print_span_with_parents(
+                    writer: &mut Writer,
+                    spans: &BTreeMap<u64, Span>,
+                    span: &Span,
+                ) -> core::fmt::Result {
                     if let Some(ref parent) = span.parent {
                         let span = spans.get(&parent.into_u64()).unwrap();
-                        print_span_with_parents(spans, span);
+                        print_span_with_parents(writer, spans, span)?;
                     }
 
-                    print!("in {} ", span.metadata.name());
+                    write!(writer, "in {} ", span.metadata.name())?;
                     for (name, value) in span.fields.iter() {
-                        print!("{}={} ", name, value);
+                        write!(writer, "{}={} ", name, value)?;
                     }
-                    println!();
+                    writeln!(writer)
                 }
 
                 let span = spans.get(&id.into_u64()).unwrap();
-                print_span_with_parents(&spans, span);
+                let _ = print_span_with_parents(&mut writer, &spans, span);
 
-                print!(
+                write!(
+                    writer,
                     "  \\ {}: {} ",
                     event.metadata().level(),
                     event.metadata().name().trim_start_matches("event ")
-                );
+                )
             }
-            None => print!(
+            None => write!(
+                writer,
                 "{}: {} ",
                 event.metadata().level(),
                 event.metadata().name().trim_start_matches("event ")
             ),
-        }
-        event.record(&mut PrintVisitor);
-        println!();
+        };
+        event.record(&mut PrintVisitor(&mut writer));
+        let _ = writeln!(writer);
     }
 
     fn enter(&self, span: &span::Id) {
-        self.current_span.store(span.into_u64(), Relaxed);
+        current_span().store(span.into_u64(), Relaxed);
     }
 
     fn exit(&self, span: &span::Id) {
@@ -152,14 +165,14 @@ impl Subscriber for PutcharSubscriber {
             .unwrap()
             .parent
             .clone();
-        self.current_span.store(
+        current_span().store(
             parent.as_ref().map(span::Id::into_u64).unwrap_or(0),
             Relaxed,
         );
     }
 
     fn current_span(&self) -> Current {
-        match self.current_span.load(Relaxed) {
+        match current_span().load(Relaxed) {
             0 => Current::none(),
             id => {
                 let metadata = self.spans.lock().get(&id).unwrap().metadata;
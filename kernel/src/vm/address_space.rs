@@ -0,0 +1,327 @@
+use alloc::collections::BTreeMap;
+use bitflags::bitflags;
+
+use crate::arch::{vm::USER_SPACE_END, FRAME_SIZE};
+
+use super::{MapFlags, PhysicalAddress, Table, TopLevelTable, VirtualAddress};
+
+bitflags! {
+    pub struct Protection: u8 {
+        const READ = 1 << 0;
+        const WRITE = 1 << 1;
+        const EXEC = 1 << 2;
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Backing {
+    /// Zero-filled memory with no particular source
+    Anonymous,
+    /// Part of a segment of an ELF file, starting at `file_offset` bytes into the file
+    Elf { file_offset: usize },
+    /// A fixed range of physical memory, starting at the given address
+    Physical(PhysicalAddress),
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// The address or size isn't a multiple of the page size
+    Unaligned,
+    /// Some of the range is outside of the user half of the address space
+    OutOfRange,
+    /// Some of the range is already mapped
+    Overlap,
+    /// Some of the range isn't mapped
+    NotMapped,
+    /// The range is mapped, but not with the permissions needed
+    AccessDenied,
+    /// User memory can't be writable and executable at the same time
+    WriteAndExecute,
+    /// The page tables couldn't be updated
+    Table,
+}
+
+/// A contiguous range of user memory with the same permissions and backing.
+#[derive(Clone, Debug)]
+pub struct Vma {
+    pub start: VirtualAddress,
+    pub size: usize,
+    pub prot: Protection,
+    pub backing: Backing,
+}
+
+impl Vma {
+    pub fn end(&self) -> VirtualAddress {
+        self.start + self.size
+    }
+
+    pub fn contains(&self, addr: VirtualAddress) -> bool {
+        self.start <= addr && addr < self.end()
+    }
+
+    /// Cut off and return the part of self from `at` onwards.
+    fn split_off(&mut self, at: VirtualAddress) -> Vma {
+        let offset = at - self.start;
+        let backing = match self.backing {
+            Backing::Anonymous => Backing::Anonymous,
+            Backing::Elf { file_offset } => Backing::Elf {
+                file_offset: file_offset + offset,
+            },
+            Backing::Physical(phys) => Backing::Physical(phys + offset),
+        };
+        let tail = Vma {
+            start: at,
+            size: self.size - offset,
+            prot: self.prot,
+            backing,
+        };
+        self.size = offset;
+        tail
+    }
+}
+
+/// The user half of a process's virtual address space.
+///
+/// Every mapping made through here is recorded as a [`Vma`], so that the kernel can tell which
+/// user addresses are valid without walking the page tables, and so that everything can be torn
+/// down when the process goes away. The page tables themselves are passed into each operation,
+/// since they're only accessible while the owning context is active.
+pub struct AddressSpace {
+    /// Keyed by start address
+    areas: BTreeMap<usize, Vma>,
+}
+
+impl AddressSpace {
+    pub const fn new() -> Self {
+        AddressSpace {
+            areas: BTreeMap::new(),
+        }
+    }
+
+    pub fn areas(&self) -> impl Iterator<Item = &Vma> {
+        self.areas.values()
+    }
+
+    /// Find the area containing `addr`, if there is one.
+    pub fn find(&self, addr: VirtualAddress) -> Option<&Vma> {
+        self.areas
+            .range(..=addr.0)
+            .next_back()
+            .map(|(_, vma)| vma)
+            .filter(|vma| vma.contains(addr))
+    }
+
+    /// Returns true if every byte of `start..start + len` is mapped with at least `prot`.
+    pub fn check(&self, start: VirtualAddress, len: usize, prot: Protection) -> bool {
+        let end = match start.0.checked_add(len) {
+            Some(end) => VirtualAddress(end),
+            None => return false,
+        };
+        let mut addr = start;
+        while addr < end {
+            match self.find(addr) {
+                Some(vma) if vma.prot.contains(prot) => addr = vma.end(),
+                _ => return false,
+            }
+        }
+        true
+    }
+
+    /// Map `size` bytes at `start` and record it. ELF-backed areas get fresh frames straight
+    /// away and physical areas are mapped to the given physical memory. Anonymous areas are only
+    /// reserved; frames are allocated when they are first touched, in
+    /// [`handle_page_fault`](super::fault::handle_page_fault).
+    pub fn map(
+        &mut self,
+        table: &mut TopLevelTable,
+        start: VirtualAddress,
+        size: usize,
+        prot: Protection,
+        backing: Backing,
+    ) -> Result<(), Error> {
+        check_range(start, size)?;
+        check_prot(prot)?;
+        if self.overlaps(start, size) {
+            return Err(Error::Overlap);
+        }
+
+        let flags = MapFlags::user(prot);
+        match backing {
+            Backing::Anonymous => Ok(()),
+            Backing::Elf { .. } => table.alloc(start, size, flags),
+            Backing::Physical(phys) => table.map_to(start, phys, size, flags),
+        }
+        .map_err(|()| Error::Table)?;
+
+        self.areas.insert(
+            start.0,
+            Vma {
+                start,
+                size,
+                prot,
+                backing,
+            },
+        );
+        Ok(())
+    }
+
+    /// Unmap every page in `start..start + size`, dropping a reference to each frame behind it
+    /// so that any that nobody else is using get freed. Parts of the range that aren't mapped are
+    /// ignored.
+    pub fn unmap(
+        &mut self,
+        table: &mut TopLevelTable,
+        start: VirtualAddress,
+        size: usize,
+    ) -> Result<(), Error> {
+        check_range(start, size)?;
+        let end = start + size;
+        self.split_at(start);
+        self.split_at(end);
+
+        let doomed: alloc::vec::Vec<usize> = self
+            .areas
+            .range(start.0..end.0)
+            .map(|(&key, _)| key)
+            .collect();
+        for key in doomed {
+            let vma = self.areas.remove(&key).unwrap();
+            table.unmap(vma.start, vma.size);
+        }
+        Ok(())
+    }
+
+    /// Change the permissions of `start..start + size`, all of which must be mapped.
+    pub fn protect(
+        &mut self,
+        table: &mut TopLevelTable,
+        start: VirtualAddress,
+        size: usize,
+        prot: Protection,
+    ) -> Result<(), Error> {
+        check_range(start, size)?;
+        check_prot(prot)?;
+        let end = start + size;
+        if !self.check(start, size, Protection::empty()) {
+            return Err(Error::NotMapped);
+        }
+        self.split_at(start);
+        self.split_at(end);
+
+        for (_, vma) in self.areas.range_mut(start.0..end.0) {
+            vma.prot = prot;
+        }
+        table.protect(start, size, MapFlags::user(prot));
+        Ok(())
+    }
+
+    /// Unmap everything and free the frames and page tables behind it, e.g. when the process is
+    /// going away. The top level table itself is left for its owner to free.
+    pub fn clear(&mut self, table: &mut TopLevelTable) {
+        self.areas.clear();
+        table.release();
+    }
+
+    /// Fill this empty address space with a copy of another one, made of `areas` and mapped by
+    /// `parent_table`. Frames are shared rather than copied: private writable pages become
+    /// read-only in both, and whoever writes to one first gets their own copy, in
+    /// [`handle_page_fault`](super::fault::handle_page_fault). The parent's side is left to
+    /// [`write_protect_private`](Self::write_protect_private), since its table has to be the
+    /// active one for that.
+    pub fn copy_on_write_from(
+        &mut self,
+        table: &mut TopLevelTable,
+        areas: &[Vma],
+        parent_table: &TopLevelTable,
+    ) -> Result<(), Error> {
+        for vma in areas {
+            let shared = matches!(vma.backing, Backing::Physical(_));
+            let mut page = vma.start;
+            while page < vma.end() {
+                if let Some((phys, flags)) = parent_table.translate(page) {
+                    let flags = match shared {
+                        true => flags,
+                        false => {
+                            if let Some(info) = crate::memory::frame::get(phys) {
+                                info.get_ref();
+                            }
+                            flags - MapFlags::WRITE
+                        }
+                    };
+                    table
+                        .map_to(page, phys, FRAME_SIZE, flags)
+                        .map_err(|()| Error::Table)?;
+                }
+                page += FRAME_SIZE;
+            }
+            self.areas.insert(vma.start.0, vma.clone());
+        }
+        Ok(())
+    }
+
+    /// Make the private writable pages read-only, once a child shares them after
+    /// [`copy_on_write_from`](Self::copy_on_write_from), so that writing to them makes a copy.
+    pub fn write_protect_private(&self, table: &mut TopLevelTable) {
+        for vma in self.areas.values() {
+            let shared = matches!(vma.backing, Backing::Physical(_));
+            if !shared && vma.prot.contains(Protection::WRITE) {
+                let read_only = MapFlags::user(vma.prot - Protection::WRITE);
+                table.protect(vma.start, vma.size, read_only);
+            }
+        }
+    }
+
+    /// Find somewhere for `size` bytes that the process hasn't asked for by address, as high up
+    /// as possible so as to stay out of the way of the executable and anything growing up from
+    /// it.
+    pub fn find_free(&self, size: usize) -> Option<VirtualAddress> {
+        // the first page stays unmapped to catch null pointers
+        let fits = |start: usize, end: usize| {
+            end.checked_sub(size)
+                .filter(|&addr| addr >= start && addr >= FRAME_SIZE)
+        };
+        let mut end = USER_SPACE_END.0;
+        for vma in self.areas.values().rev() {
+            if let Some(addr) = fits(vma.end().0, end) {
+                return Some(VirtualAddress(addr));
+            }
+            end = core::cmp::min(end, vma.start.0);
+        }
+        fits(0, end).map(VirtualAddress)
+    }
+
+    fn overlaps(&self, start: VirtualAddress, size: usize) -> bool {
+        let end = start + size;
+        if self.find(start).is_some() {
+            return true;
+        }
+        self.areas.range(start.0..end.0).next().is_some()
+    }
+
+    /// Make sure no area straddles `addr`, by splitting the one that does.
+    fn split_at(&mut self, addr: VirtualAddress) {
+        let key = match self.find(addr) {
+            Some(vma) if vma.start != addr => vma.start.0,
+            _ => return,
+        };
+        let tail = self.areas.get_mut(&key).unwrap().split_off(addr);
+        self.areas.insert(addr.0, tail);
+    }
+}
+
+fn check_prot(prot: Protection) -> Result<(), Error> {
+    match prot.contains(Protection::WRITE | Protection::EXEC) {
+        true => Err(Error::WriteAndExecute),
+        false => Ok(()),
+    }
+}
+
+fn check_range(start: VirtualAddress, size: usize) -> Result<(), Error> {
+    if start.0 % FRAME_SIZE != 0 || size % FRAME_SIZE != 0 {
+        return Err(Error::Unaligned);
+    }
+    match start.0.checked_add(size) {
+        Some(end) if end <= USER_SPACE_END.0 => Ok(()),
+        _ => Err(Error::OutOfRange),
+    }
+}
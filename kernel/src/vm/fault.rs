@@ -0,0 +1,97 @@
+use crate::{
+    arch::{vm::phys_to_virt, FRAME_SIZE},
+    context::ActiveContextHandle,
+    memory::{frame::FrameOwner, FRAME_ALLOCATOR},
+};
+
+use super::{
+    address_space::Error, Backing, MapFlags, Protection, Table, TopLevelTable, VirtualAddress,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FaultKind {
+    /// There was no translation for the address
+    NotPresent,
+    /// There was a translation, but it doesn't allow the access
+    Permission,
+}
+
+#[derive(Debug)]
+pub struct PageFault {
+    pub address: VirtualAddress,
+    /// What the faulting access was trying to do
+    pub access: Protection,
+    pub kind: FaultKind,
+}
+
+/// Try to resolve a fault on a user address in the active context. If this returns Ok, the
+/// faulting access can be retried.
+#[tracing::instrument(level = "debug", skip(context), err(Debug))]
+pub fn handle_page_fault(
+    context: &mut ActiveContextHandle,
+    fault: &PageFault,
+) -> Result<(), Error> {
+    let (space, table) = context.memory();
+    let vma = space.find(fault.address).ok_or(Error::NotMapped)?;
+    if !vma.prot.contains(fault.access) {
+        return Err(Error::AccessDenied);
+    }
+
+    match (fault.kind, vma.backing) {
+        // anonymous memory is only backed once somebody touches it
+        (FaultKind::NotPresent, Backing::Anonymous) => {
+            let page = VirtualAddress(fault.address.0 / FRAME_SIZE * FRAME_SIZE);
+            map_zeroed_page(table, page, MapFlags::user(vma.prot))
+        }
+        // everything else is mapped up front, so it really isn't there
+        (FaultKind::NotPresent, _) => Err(Error::NotMapped),
+        // the area is writable but the page isn't, so it must be shared after a fork
+        (FaultKind::Permission, Backing::Anonymous | Backing::Elf { .. })
+            if fault.access == Protection::WRITE =>
+        {
+            let page = VirtualAddress(fault.address.0 / FRAME_SIZE * FRAME_SIZE);
+            copy_on_write(table, page, MapFlags::user(vma.prot))
+        }
+        (FaultKind::Permission, _) => Err(Error::AccessDenied),
+    }
+}
+
+/// Give the active context its own copy of a shared page, and make it writable.
+fn copy_on_write(
+    table: &mut TopLevelTable,
+    page: VirtualAddress,
+    flags: MapFlags,
+) -> Result<(), Error> {
+    let (old, _) = table.translate(page).ok_or(Error::NotMapped)?;
+    let last_user = crate::memory::frame::get(old).map_or(true, |info| info.refcount() == 1);
+    if last_user {
+        // everyone else has let go of it already, so there's no need to copy
+        table.protect(page, FRAME_SIZE, flags);
+        return Ok(());
+    }
+
+    let new = FRAME_ALLOCATOR.lock().alloc();
+    crate::memory::frame::set_owner(new, FrameOwner::UserAnon);
+    unsafe {
+        core::ptr::copy_nonoverlapping(
+            phys_to_virt(old).0 as *const u8,
+            phys_to_virt(new).0 as *mut u8,
+            FRAME_SIZE,
+        );
+    }
+    // this drops our reference to the old frame
+    table.remap(page, new, flags).map_err(|()| Error::Table)
+}
+
+fn map_zeroed_page(
+    table: &mut TopLevelTable,
+    page: VirtualAddress,
+    flags: MapFlags,
+) -> Result<(), Error> {
+    let frame = FRAME_ALLOCATOR.lock().alloc();
+    crate::memory::frame::set_owner(frame, FrameOwner::UserAnon);
+    unsafe { core::ptr::write_bytes(phys_to_virt(frame).0 as *mut u8, 0, FRAME_SIZE) };
+    table
+        .map_to(page, frame, FRAME_SIZE, flags)
+        .map_err(|()| Error::Table)
+}
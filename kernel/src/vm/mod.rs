@@ -1,10 +1,20 @@
 use core::mem::MaybeUninit;
 
-use crate::memory::Chunk;
+use bitflags::bitflags;
+
+use crate::{
+    arch::FRAME_SIZE,
+    memory::{frame::FrameOwner, Chunk},
+};
 
 pub use crate::arch::vm::TopLevelTable;
+pub use address_space::{AddressSpace, Backing, Protection, Vma};
+
+pub mod address_space;
+pub mod fault;
+pub mod user;
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
 #[repr(transparent)]
 pub struct PhysicalAddress(pub usize);
 
@@ -38,7 +48,7 @@ impl core::ops::Sub<PhysicalAddress> for PhysicalAddress {
     }
 }
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
 #[repr(transparent)]
 pub struct VirtualAddress(pub usize);
 
@@ -78,6 +88,45 @@ impl core::ops::Sub<VirtualAddress> for VirtualAddress {
     }
 }
 
+bitflags! {
+    /// How a page may be accessed, and by whom.
+    pub struct MapFlags: u8 {
+        const READ = 1 << 0;
+        const WRITE = 1 << 1;
+        const EXEC = 1 << 2;
+        /// Accessible from userspace. User mappings can't be both writable and executable.
+        const USER = 1 << 3;
+        /// The same in every address space
+        const GLOBAL = 1 << 4;
+        /// Device memory, i.e. uncached and not reordered, for MMIO
+        const DEVICE = 1 << 5;
+    }
+}
+
+impl MapFlags {
+    pub const KERNEL_CODE: Self = Self {
+        bits: Self::READ.bits | Self::EXEC.bits | Self::GLOBAL.bits,
+    };
+    pub const KERNEL_RO: Self = Self {
+        bits: Self::READ.bits | Self::GLOBAL.bits,
+    };
+    pub const KERNEL_DATA: Self = Self {
+        bits: Self::READ.bits | Self::WRITE.bits | Self::GLOBAL.bits,
+    };
+    pub const KERNEL_DEVICE: Self = Self {
+        bits: Self::KERNEL_DATA.bits | Self::DEVICE.bits,
+    };
+
+    /// The flags for user memory with the given protection.
+    pub fn user(prot: Protection) -> Self {
+        let mut flags = MapFlags::USER;
+        flags.set(MapFlags::READ, prot.contains(Protection::READ));
+        flags.set(MapFlags::WRITE, prot.contains(Protection::WRITE));
+        flags.set(MapFlags::EXEC, prot.contains(Protection::EXEC));
+        flags
+    }
+}
+
 pub trait Table: Sized {
     /// Returns Err(()) and doesn't map anything if any virtual address in this range is already
     /// mapped
@@ -87,15 +136,55 @@ pub trait Table: Sized {
         virt: VirtualAddress,
         phys: PhysicalAddress,
         size: usize,
+        flags: MapFlags,
     ) -> Result<(), ()>;
 
+    /// Unmap every page in the range, dropping a reference to any frames that were allocated for
+    /// user memory. Next-level tables that end up empty are freed, and the TLB is invalidated for
+    /// every page that was mapped.
     fn unmap(&mut self, virt: VirtualAddress, size: usize);
 
+    /// Point the page at `virt`, which has to be mapped already, at `phys` instead. The old
+    /// translation is invalidated in the TLB before the new one goes in, and the reference to the
+    /// old frame is dropped if it was allocated for user memory. Like [`protect`](Self::protect),
+    /// this has to be the active table, or the kernel's.
+    fn remap(
+        &mut self,
+        virt: VirtualAddress,
+        phys: PhysicalAddress,
+        flags: MapFlags,
+    ) -> Result<(), ()>;
+
+    /// Change the flags of every page that is mapped in the range, invalidating them in the TLB
+    /// under the active ASID. That means this has to be the active table, or the kernel's.
+    fn protect(&mut self, virt: VirtualAddress, size: usize, flags: MapFlags);
+
+    /// Find where `virt` is mapped to, and how.
+    fn translate(&self, virt: VirtualAddress) -> Option<(PhysicalAddress, MapFlags)>;
+
     fn clear<'a>(this: &'a mut MaybeUninit<Self>) -> &'a mut Self;
 
-    fn alloc(&mut self, mut virt: VirtualAddress, mut size: usize) -> Result<(), ()> {
+    /// Returns true if nothing at all is mapped through this table.
+    fn is_empty(&self) -> bool;
+
+    /// Unmap everything, freeing the next-level tables and any frames that were allocated for
+    /// user memory. Frames belonging to anything else, like device memory, are left alone.
+    /// Everything under the active ASID is invalidated in the TLB, so this has to be the active
+    /// table.
+    fn release(&mut self);
+
+    fn alloc(
+        &mut self,
+        mut virt: VirtualAddress,
+        mut size: usize,
+        flags: MapFlags,
+    ) -> Result<(), ()> {
         self.unmap(virt, size);
         size = (size + 4095) / 4096 * 4096;
+        let owner = match flags.contains(MapFlags::USER) {
+            true => FrameOwner::UserAnon,
+            false => FrameOwner::KernelHeap,
+        };
         while size > 0 {
             let Chunk {
                 phys: chunk_phys,
@@ -104,16 +193,13 @@ pub trait Table: Sized {
                 let mut frame_alloc = crate::memory::FRAME_ALLOCATOR.lock();
                 frame_alloc.alloc_range(size)
             };
-            self.map_to(virt, chunk_phys, size)?;
+            for offset in (0..chunk_size).step_by(FRAME_SIZE) {
+                crate::memory::frame::set_owner(chunk_phys + offset, owner);
+            }
+            self.map_to(virt, chunk_phys, chunk_size, flags)?;
             size -= chunk_size;
             virt += chunk_size;
         }
         Ok(())
     }
 }
-
-pub struct Mapping {
-    virt: VirtualAddress,
-    phys: PhysicalAddress,
-    size: usize,
-}
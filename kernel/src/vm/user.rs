@@ -0,0 +1,79 @@
+// Accessing user memory from the kernel
+//
+// A process can pass the kernel any pointer it likes, so nothing the kernel reads or writes on
+// its behalf can be trusted to be mapped. These functions check the range against the process's
+// areas first, and if a page still can't be faulted in, the copy stops and returns an error
+// instead of the kernel crashing.
+
+use crate::{
+    arch::{usercopy, FRAME_SIZE},
+    context::ActiveContextHandle,
+};
+
+use super::{Protection, VirtualAddress};
+
+/// Some of the user memory involved isn't mapped, or doesn't allow the access.
+#[derive(Debug)]
+pub struct InvalidAddress;
+
+/// Copy `dst.len()` bytes from user memory at `src`.
+pub fn copy_from_user(
+    context: &mut ActiveContextHandle,
+    dst: &mut [u8],
+    src: VirtualAddress,
+) -> Result<(), InvalidAddress> {
+    check(context, src, dst.len(), Protection::READ)?;
+    unsafe { usercopy::copy(dst.as_mut_ptr(), src.0 as *const u8, dst.len()) }
+        .map_err(|()| InvalidAddress)
+}
+
+/// Copy `src` to user memory at `dst`.
+pub fn copy_to_user(
+    context: &mut ActiveContextHandle,
+    dst: VirtualAddress,
+    src: &[u8],
+) -> Result<(), InvalidAddress> {
+    check(context, dst, src.len(), Protection::WRITE)?;
+    unsafe { usercopy::copy(dst.0 as *mut u8, src.as_ptr(), src.len()) }
+        .map_err(|()| InvalidAddress)
+}
+
+/// Copy a nul-terminated string from user memory at `src` into `dst`, and return its length. If
+/// there's no nul in the first `dst.len()` bytes, this returns `dst.len()`.
+///
+/// The string is allowed to end right before an unmapped page, so only the pages it actually
+/// covers need to be valid.
+pub fn strncpy_from_user(
+    context: &mut ActiveContextHandle,
+    dst: &mut [u8],
+    src: VirtualAddress,
+) -> Result<usize, InvalidAddress> {
+    let mut copied = 0;
+    while copied < dst.len() {
+        let addr = src.0.checked_add(copied).ok_or(InvalidAddress)?;
+        let chunk = core::cmp::min(dst.len() - copied, FRAME_SIZE - addr % FRAME_SIZE);
+        check(context, VirtualAddress(addr), chunk, Protection::READ)?;
+        let len =
+            unsafe { usercopy::strncpy(dst[copied..].as_mut_ptr(), addr as *const u8, chunk) }
+                .map_err(|()| InvalidAddress)?;
+        copied += len;
+        if len < chunk {
+            break;
+        }
+    }
+    Ok(copied)
+}
+
+/// Check that `len` bytes at `start` are all in areas that allow `prot`, without touching them.
+/// Useful for finding out whether a copy can work before doing something that can't be undone.
+pub fn check(
+    context: &mut ActiveContextHandle,
+    start: VirtualAddress,
+    len: usize,
+    prot: Protection,
+) -> Result<(), InvalidAddress> {
+    match context.memory().0.check(start, len, prot) {
+        true => Ok(()),
+        false => Err(InvalidAddress),
+    }
+}
@@ -0,0 +1,47 @@
+//! The arguments the program was started with.
+
+use core::{
+    ffi::{c_char, CStr},
+    sync::atomic::{AtomicPtr, AtomicUsize, Ordering},
+};
+
+static ARGC: AtomicUsize = AtomicUsize::new(0);
+static ARGV: AtomicPtr<*const c_char> = AtomicPtr::new(core::ptr::null_mut());
+
+/// Remember where the arguments are, for [`args`]. `argv` must point to `argc` nul-terminated
+/// strings that live for the rest of the program.
+pub(crate) unsafe fn init(argc: usize, argv: *const *const c_char) {
+    ARGC.store(argc, Ordering::Relaxed);
+    ARGV.store(argv as *mut _, Ordering::Relaxed);
+}
+
+/// The program's arguments, starting with its own name. Any that aren't valid UTF-8 come out as
+/// empty strings.
+pub fn args() -> Args {
+    Args { idx: 0 }
+}
+
+pub struct Args {
+    idx: usize,
+}
+
+impl Iterator for Args {
+    type Item = &'static str;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.idx >= ARGC.load(Ordering::Relaxed) {
+            return None;
+        }
+        // safety: guaranteed by init
+        let arg = unsafe { CStr::from_ptr(*ARGV.load(Ordering::Relaxed).add(self.idx)) };
+        self.idx += 1;
+        Some(arg.to_str().unwrap_or(""))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let len = ARGC.load(Ordering::Relaxed) - self.idx;
+        (len, Some(len))
+    }
+}
+
+impl ExactSizeIterator for Args {}
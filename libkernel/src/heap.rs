@@ -0,0 +1,60 @@
+// The global allocator, which gets memory from the kernel a chunk at a time
+
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    ptr::NonNull,
+};
+
+use abi::{map, prot, Error};
+use linked_list_allocator::Heap;
+use spin::Mutex;
+
+use crate::syscall;
+
+/// Where the heap would like to start. It grows upwards from here, and the kernel puts other
+/// mappings as high as it can, so there should be plenty of room.
+const HEAP_START: usize = 0x0000_1000_0000_0000;
+/// The least to ask the kernel for at once
+const CHUNK_SIZE: usize = 64 * 1024;
+
+#[global_allocator]
+static ALLOCATOR: Allocator = Allocator(Mutex::new(Heap::empty()));
+
+struct Allocator(Mutex<Heap>);
+
+unsafe impl GlobalAlloc for Allocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let mut heap = self.0.lock();
+        if let Ok(ptr) = heap.allocate_first_fit(layout) {
+            return ptr.as_ptr();
+        }
+        // leave room for aligning the allocation, however the free space is arranged
+        let needed = layout.size() + layout.align();
+        let size = needed.div_ceil(CHUNK_SIZE) * CHUNK_SIZE;
+        if grow(&mut heap, size).is_err() {
+            return core::ptr::null_mut();
+        }
+        heap.allocate_first_fit(layout)
+            .map_or(core::ptr::null_mut(), |ptr| ptr.as_ptr())
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.0
+            .lock()
+            .deallocate(NonNull::new_unchecked(ptr), layout)
+    }
+}
+
+/// Map `size` more bytes onto the top of the heap.
+fn grow(heap: &mut Heap, size: usize) -> Result<(), Error> {
+    let rw = prot::READ | prot::WRITE;
+    if heap.size() == 0 {
+        let start = syscall::map(HEAP_START, size, rw, 0)?;
+        unsafe { heap.init(start, size) };
+    } else {
+        // the heap has to stay contiguous, so if something else is in the way, that's the end
+        syscall::map(heap.top() as usize, size, rw, map::FIXED)?;
+        unsafe { heap.extend(size) };
+    }
+    Ok(())
+}
@@ -0,0 +1,41 @@
+//! Everything a program needs to run on the kernel: syscall wrappers, printing, a heap, and the
+//! entry point and panic handler.
+//!
+//! Programs are `#![no_std]` and `#![no_main]`, and name their main function with [`entry!`]:
+//!
+//! ```ignore
+//! #![no_std]
+//! #![no_main]
+//!
+//! libkernel::entry!(main);
+//!
+//! fn main() -> i32 {
+//!     libkernel::println!("hello from {}", libkernel::env::args().next().unwrap_or("?"));
+//!     0
+//! }
+//! ```
+
+#![no_std]
+
+extern crate alloc;
+
+pub use abi;
+
+pub mod env;
+mod heap;
+pub mod io;
+mod rt;
+pub mod syscall;
+
+/// Make `$main`, a `fn() -> i32`, the function that runs when the program starts. Its return
+/// value is the exit code.
+#[macro_export]
+macro_rules! entry {
+    ($main:path) => {
+        #[no_mangle]
+        fn __libkernel_main() -> i32 {
+            let main: fn() -> i32 = $main;
+            main()
+        }
+    };
+}
@@ -0,0 +1,36 @@
+// The entry point and panic handler
+
+use core::{arch::global_asm, ffi::c_char, fmt::Write};
+
+use crate::{io::Stdout, syscall};
+
+// the kernel leaves sp pointing at argc, followed by argv. zero the frame pointer and link
+// register so that anything walking the stack knows where to stop
+global_asm!(
+    ".globl _start",
+    "_start:",
+    "mov x29, #0",
+    "mov x30, #0",
+    "mov x0, sp",
+    "b {start}",
+    start = sym start,
+);
+
+extern "Rust" {
+    /// Defined by [`entry!`](crate::entry)
+    fn __libkernel_main() -> i32;
+}
+
+unsafe extern "C" fn start(sp: *const usize) -> ! {
+    let argc = *sp;
+    let argv = sp.add(1) as *const *const c_char;
+    crate::env::init(argc, argv);
+    syscall::exit(__libkernel_main())
+}
+
+#[panic_handler]
+fn panic_handler(info: &core::panic::PanicInfo) -> ! {
+    // this prints the location as well as the message
+    let _ = writeln!(Stdout, "{info}");
+    syscall::exit(101)
+}
@@ -0,0 +1,99 @@
+//! Safe wrappers around every syscall. See [`abi::Syscall`] for what each one does.
+
+use core::{arch::asm, ffi::CStr};
+
+use abi::{Error, Syscall};
+
+/// Make a syscall, passing unused arguments as 0.
+///
+/// # Safety
+/// The kernel might read or write any memory the arguments point to.
+pub unsafe fn raw_syscall(num: Syscall, args: [usize; 6]) -> Result<usize, Error> {
+    let ret;
+    asm!(
+        "svc #0",
+        in("x8") num as usize,
+        inlateout("x0") args[0] => ret,
+        in("x1") args[1],
+        in("x2") args[2],
+        in("x3") args[3],
+        in("x4") args[4],
+        in("x5") args[5],
+        options(nostack),
+    );
+    abi::decode_result(ret)
+}
+
+pub fn exit(code: i32) -> ! {
+    let _ = unsafe { raw_syscall(Syscall::Exit, [code as usize, 0, 0, 0, 0, 0]) };
+    // panicking here would go through the panic handler, which exits again
+    loop {
+        unsafe { asm!("wfe", options(nomem, nostack)) };
+    }
+}
+
+/// Write some bytes to the console.
+pub fn print(bytes: &[u8]) -> Result<(), Error> {
+    let args = [bytes.as_ptr() as usize, bytes.len(), 0, 0, 0, 0];
+    unsafe { raw_syscall(Syscall::Print, args) }.map(|_| ())
+}
+
+/// Read some input from the console into `buf`, waiting for some if there isn't any. Returns how
+/// many bytes were read.
+pub fn read(buf: &mut [u8]) -> Result<usize, Error> {
+    let args = [buf.as_mut_ptr() as usize, buf.len(), 0, 0, 0, 0];
+    unsafe { raw_syscall(Syscall::Read, args) }
+}
+
+pub fn yield_now() {
+    let _ = unsafe { raw_syscall(Syscall::Yield, [0; 6]) };
+}
+
+/// Start running the program at `path` in the initrd, and return its process id.
+pub fn spawn(path: &CStr) -> Result<usize, Error> {
+    unsafe { raw_syscall(Syscall::Spawn, [path.as_ptr() as usize, 0, 0, 0, 0, 0]) }
+}
+
+/// Wait for the child process `pid` to exit, and return its exit code.
+pub fn wait(pid: usize) -> Result<i32, Error> {
+    unsafe { raw_syscall(Syscall::Wait, [pid, 0, 0, 0, 0, 0]) }.map(|code| code as u32 as i32)
+}
+
+/// Make a copy of this process. Returns the child's process id in the parent, and 0 in the
+/// child.
+pub fn fork() -> Result<usize, Error> {
+    unsafe { raw_syscall(Syscall::Fork, [0; 6]) }
+}
+
+/// Map `len` bytes of zeroed memory with the permissions `prot` (from [`abi::prot`]), at `addr`
+/// if possible or exactly there if `flags` has [`abi::map::FIXED`]. Nothing that's already
+/// mapped is ever replaced, so this can't pull memory out from under anything.
+pub fn map(addr: usize, len: usize, prot: usize, flags: usize) -> Result<*mut u8, Error> {
+    unsafe { raw_syscall(Syscall::Map, [addr, len, prot, flags, 0, 0]) }.map(|addr| addr as *mut u8)
+}
+
+/// Unmap every page in `addr..addr + len`.
+///
+/// # Safety
+/// Nothing can be using the memory any more, including the heap.
+pub unsafe fn unmap(addr: *mut u8, len: usize) -> Result<(), Error> {
+    raw_syscall(Syscall::Unmap, [addr as usize, len, 0, 0, 0, 0]).map(|_| ())
+}
+
+/// Change the permissions of every page in `addr..addr + len` to `prot` (from [`abi::prot`]).
+///
+/// # Safety
+/// Nothing can be relying on the old permissions, e.g. by holding a `&mut` into memory that
+/// becomes read-only.
+pub unsafe fn protect(addr: *mut u8, len: usize, prot: usize) -> Result<(), Error> {
+    raw_syscall(Syscall::Protect, [addr as usize, len, prot, 0, 0, 0]).map(|_| ())
+}
+
+/// Power the machine off or restart it, with `how` from [`abi::reboot`]. Only returns if the
+/// caller isn't allowed to, or `how` is nonsense.
+pub fn reboot(how: usize) -> Error {
+    match unsafe { raw_syscall(Syscall::Reboot, [how, 0, 0, 0, 0, 0]) } {
+        Ok(_) => unreachable!("reboot returned"),
+        Err(e) => e,
+    }
+}